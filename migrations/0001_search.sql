@@ -0,0 +1,50 @@
+-- Mirror of the messages of every room, searched by /api/search.
+CREATE TABLE messages (
+  room TEXT NOT NULL,
+  id INTEGER NOT NULL,
+  author TEXT NOT NULL,
+  content TEXT NOT NULL,
+  -- Milliseconds since the Unix epoch.
+  timestamp INTEGER NOT NULL,
+  has_attachment INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (room, id)
+);
+
+CREATE INDEX messages_by_time ON messages (timestamp);
+
+CREATE VIRTUAL TABLE messages_fts USING fts5 (
+  content,
+  content = 'messages',
+  content_rowid = 'rowid',
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER messages_ai AFTER INSERT ON messages BEGIN
+  INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+END;
+
+CREATE TRIGGER messages_ad AFTER DELETE ON messages BEGIN
+  INSERT INTO messages_fts (messages_fts, rowid, content)
+    VALUES ('delete', old.rowid, old.content);
+END;
+
+CREATE TRIGGER messages_au AFTER UPDATE ON messages BEGIN
+  INSERT INTO messages_fts (messages_fts, rowid, content)
+    VALUES ('delete', old.rowid, old.content);
+  INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+END;
+
+-- Mirror of the access settings of the rooms. Only rooms listed here as not
+-- private are readable by all; rooms missing here are searched as private,
+-- so only by their members.
+CREATE TABLE rooms (
+  room TEXT PRIMARY KEY,
+  private INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE room_members (
+  room TEXT NOT NULL,
+  user TEXT NOT NULL,
+  banned INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (room, user)
+);
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::auth::{self, Credentials, PasswordHash};
+use crate::error::{ApiResult, AppError};
+use crate::storage;
+use crate::validation::Validator;
+
+/// Storage key prefix of the user accounts.
+const USER_PREFIX: &str = "user:";
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    name: String,
+    password: PasswordHash,
+    created_at: u64,
+}
+
+/// Registered users and their password hashes.
+#[allow(dead_code)]
+#[durable_object]
+pub struct Accounts {
+    env: Env,
+    state: State,
+}
+
+impl Accounts {
+    async fn handle(&mut self, mut req: Request) -> ApiResult<Response> {
+        let path = req.path();
+        if !matches!(req.method(), Method::Post) {
+            return Err(AppError::MethodNotAllowed("POST"));
+        }
+
+        let credentials = req
+            .json::<Credentials>()
+            .await
+            .map_err(AppError::bad_request)?;
+        let key = format!("{USER_PREFIX}{}", credentials.name);
+        let mut storage = self.state.storage();
+
+        match path.as_str() {
+            "/register" => {
+                if !auth::is_valid_account_name(&credentials.name) {
+                    return Err(AppError::BadRequest(format!(
+                        "Invalid user name: {}",
+                        credentials.name
+                    )));
+                }
+                let mut validator = Validator::default();
+                if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+                    validator.invalid(
+                        "password",
+                        format!("must be at least {MIN_PASSWORD_LENGTH} characters"),
+                    );
+                }
+                validator.finish()?;
+
+                if storage::get::<Account>(&storage, &key).await?.is_some() {
+                    return Err(AppError::Conflict(format!(
+                        "User {} already exists",
+                        credentials.name
+                    )));
+                }
+
+                let account = Account {
+                    name: credentials.name,
+                    password: PasswordHash::new(&credentials.password)?,
+                    created_at: Date::now().as_millis(),
+                };
+                storage::put(&mut storage, &key, &account).await?;
+
+                Ok(
+                    Response::from_json(&serde_json::json!({ "name": account.name }))?
+                        .with_status(201),
+                )
+            }
+            "/verify" => match storage::get::<Account>(&storage, &key).await? {
+                Some(account) if account.password.verify(&credentials.password) => Ok(
+                    Response::from_json(&serde_json::json!({ "name": account.name }))?,
+                ),
+                _ => Err(AppError::Unauthorized(
+                    "Invalid user name or password".to_string(),
+                )),
+            },
+            _ => Err(AppError::NotFound(format!("No such path: {path}"))),
+        }
+    }
+}
+
+#[durable_object]
+impl DurableObject for Accounts {
+    fn new(state: State, env: Env) -> Self {
+        Self { env, state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        match self.handle(req).await {
+            Ok(response) => Ok(response),
+            Err(error) => error.into_worker_response(),
+        }
+    }
+}
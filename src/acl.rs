@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiResult, AppError};
+
+/// Role of a room member. Each role may do everything the roles before it may.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// May read a private room, but not post.
+    ReadOnly,
+    Member,
+    /// May delete messages, invite, kick and ban.
+    Moderator,
+    /// May also change roles and the room's access settings.
+    Owner,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::ReadOnly => "read-only",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        })
+    }
+}
+
+/// Members and access settings of a room.
+///
+/// Rooms created before memberships existed have no ACL and stay open to
+/// everyone, see [`Acl::can_read`] and [`Acl::can_post`].
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Acl {
+    /// Private rooms can only be read by their members.
+    pub private: bool,
+    /// Invite-only rooms can only be joined by invited users.
+    pub invite_only: bool,
+    pub members: BTreeMap<String, Role>,
+    pub invited: BTreeSet<String>,
+    pub banned: BTreeSet<String>,
+}
+
+impl Acl {
+    pub fn new(owner: &str, settings: &AccessSettings) -> Self {
+        Acl {
+            private: settings.private.unwrap_or(false),
+            invite_only: settings.invite_only.unwrap_or(false),
+            members: BTreeMap::from([(owner.to_string(), Role::Owner)]),
+            invited: BTreeSet::new(),
+            banned: BTreeSet::new(),
+        }
+    }
+
+    pub fn role(&self, user: &str) -> Option<Role> {
+        self.members.get(user).copied()
+    }
+
+    /// The users who may read the room when it is private.
+    pub fn readers(&self) -> Vec<String> {
+        self.members
+            .keys()
+            .filter(|user| !self.banned.contains(*user))
+            .cloned()
+            .collect()
+    }
+
+    pub fn can_read(acl: Option<&Acl>, user: Option<&str>) -> ApiResult<()> {
+        let Some(acl) = acl else {
+            return Ok(());
+        };
+
+        if user.is_some_and(|user| acl.banned.contains(user)) {
+            return Err(AppError::Forbidden(
+                "You are banned from this room".to_string(),
+            ));
+        }
+        if acl.private && user.and_then(|user| acl.role(user)).is_none() {
+            return Err(AppError::Forbidden(
+                "Only members may read this room".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn can_post(acl: Option<&Acl>, user: &str) -> ApiResult<()> {
+        let Some(acl) = acl else {
+            return Ok(());
+        };
+
+        if acl.banned.contains(user) {
+            return Err(AppError::Forbidden(
+                "You are banned from this room".to_string(),
+            ));
+        }
+        if acl.role(user) < Some(Role::Member) {
+            return Err(AppError::Forbidden(
+                "Only members may post in this room".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the room would be left without an owner if `user` lost their
+    /// role.
+    pub fn is_last_owner(&self, user: &str) -> bool {
+        self.role(user) == Some(Role::Owner)
+            && self
+                .members
+                .values()
+                .filter(|role| **role == Role::Owner)
+                .count()
+                == 1
+    }
+}
+
+/// Access settings of a room, as submitted by its owner. Settings left out
+/// are unchanged.
+#[derive(Serialize, Deserialize, Default)]
+pub struct AccessSettings {
+    pub private: Option<bool>,
+    pub invite_only: Option<bool>,
+}
+
+/// A room ACL as created along with the room.
+#[derive(Serialize, Deserialize)]
+pub struct NewAcl {
+    pub owner: String,
+    #[serde(flatten)]
+    pub settings: AccessSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Invite {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleChange {
+    pub role: Role,
+}
@@ -0,0 +1,330 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::auth::{self, requester};
+use crate::directory;
+use crate::error::{ApiResult, AppError};
+use crate::storage;
+use crate::validation::Validator;
+
+/// Storage key prefix of the API keys.
+const KEY_PREFIX: &str = "key:";
+
+/// Storage key prefix of the index of the keys by owner, followed by the
+/// owner's name and the key's id.
+const OWNER_PREFIX: &str = "owner:";
+
+/// Set once the keys minted before the owner index existed were indexed.
+const OWNER_INDEX_KEY: &str = "owner_index";
+
+const MAX_SCOPES: usize = 50;
+
+/// Prefix of API key tokens, telling them apart from session tokens.
+pub const TOKEN_PREFIX: &str = "ck_";
+
+/// Rooms and access an API key is limited to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyScope {
+    /// Address of the room, or `*` for every room.
+    pub room: String,
+    pub access: Access,
+}
+
+/// Access granted by a [`KeyScope`]. Write access includes read access.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewApiKey {
+    /// Label telling the owner's keys apart, e.g. the bot using it.
+    pub name: String,
+    pub scopes: Vec<KeyScope>,
+}
+
+impl NewApiKey {
+    pub fn validate(&self) -> ApiResult<()> {
+        let mut validator = Validator::default();
+        if self.name.trim().is_empty() {
+            validator.invalid("name", "must not be empty");
+        }
+        validate_scopes(&mut validator, &self.scopes);
+
+        validator.finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiKeyUpdate {
+    pub scopes: Vec<KeyScope>,
+}
+
+impl ApiKeyUpdate {
+    pub fn validate(&self) -> ApiResult<()> {
+        let mut validator = Validator::default();
+        validate_scopes(&mut validator, &self.scopes);
+
+        validator.finish()
+    }
+}
+
+/// Checks that a key has at least one scope, and that each names `*` or a
+/// valid room address, at most once.
+fn validate_scopes(validator: &mut Validator, scopes: &[KeyScope]) {
+    if scopes.is_empty() || scopes.len() > MAX_SCOPES {
+        validator.invalid(
+            "scopes",
+            format!("must hold between 1 and {MAX_SCOPES} scopes"),
+        );
+    }
+
+    for (i, scope) in scopes.iter().enumerate() {
+        let name = format!("scopes[{i}].room");
+        if scope.room != "*"
+            && !directory::is_valid_room_name(&scope.room)
+            && !directory::is_unique_id(&scope.room)
+        {
+            validator.invalid(&name, "must be a room name, a room id or `*`");
+        } else if let Some(first) = scopes[..i]
+            .iter()
+            .position(|other| other.room == scope.room)
+        {
+            validator.invalid(&name, format!("duplicates scopes[{first}].room"));
+        }
+    }
+}
+
+/// An API key as shown to its owner. The token itself is only returned when
+/// the key is minted, see [`MintedApiKey`].
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// User the key acts on behalf of.
+    pub owner: String,
+    pub scopes: Vec<KeyScope>,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MintedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredApiKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// SHA-256 of the token's secret. Secrets are random, so a plain hash is
+    /// as good as a password hash here.
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KeyToken {
+    pub token: String,
+}
+
+/// Splits an API key token into its id and secret.
+fn parse_token(token: &str) -> Option<(&str, &str)> {
+    token.strip_prefix(TOKEN_PREFIX)?.split_once('_')
+}
+
+fn hash_secret(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn key_key(id: &str) -> String {
+    format!("{KEY_PREFIX}{id}")
+}
+
+fn owner_prefix(owner: &str) -> String {
+    format!("{OWNER_PREFIX}{owner}:")
+}
+
+fn owner_key(owner: &str, id: &str) -> String {
+    format!("{}{id}", owner_prefix(owner))
+}
+
+/// API keys letting bots act on behalf of users, limited to some rooms.
+#[allow(dead_code)]
+#[durable_object]
+pub struct ApiKeys {
+    env: Env,
+    state: State,
+}
+
+impl ApiKeys {
+    async fn get_key(&self, id: &str) -> ApiResult<StoredApiKey> {
+        storage::get::<StoredApiKey>(&self.state.storage(), &key_key(id))
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("No such API key: {id}")))
+    }
+
+    /// Looks up a key the requester owns. Keys of other users are reported
+    /// as missing rather than forbidden, so ids do not leak.
+    async fn owned_key(&self, id: &str, requester: &str) -> ApiResult<StoredApiKey> {
+        match self.get_key(id).await? {
+            stored if stored.key.owner == requester => Ok(stored),
+            _ => Err(AppError::NotFound(format!("No such API key: {id}"))),
+        }
+    }
+
+    /// The keys of an owner, through the owner index. Names are not escaped in
+    /// index keys, so keys owned by others are filtered out.
+    async fn owner_keys(&self, owner: &str) -> ApiResult<Vec<ApiKey>> {
+        self.index_owners().await?;
+
+        let storage = self.state.storage();
+        let ids =
+            storage::list::<String>(&storage, ListOptions::new().prefix(&owner_prefix(owner)))
+                .await?;
+
+        let mut keys = Vec::with_capacity(ids.len());
+        for (_, id) in ids {
+            if let Some(stored) = storage::get::<StoredApiKey>(&storage, &key_key(&id)).await? {
+                if stored.key.owner == owner {
+                    keys.push(stored.key);
+                }
+            }
+        }
+        keys.sort_by_key(|key| key.created_at);
+
+        Ok(keys)
+    }
+
+    /// Adds the keys minted before the owner index existed to it, once.
+    async fn index_owners(&self) -> Result<()> {
+        let mut storage = self.state.storage();
+        if storage::get::<bool>(&storage, OWNER_INDEX_KEY)
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let keys =
+            storage::list::<StoredApiKey>(&storage, ListOptions::new().prefix(KEY_PREFIX)).await?;
+        for (_, stored) in keys {
+            storage::put(
+                &mut storage,
+                &owner_key(&stored.key.owner, &stored.key.id),
+                &stored.key.id,
+            )
+            .await?;
+        }
+
+        storage::put(&mut storage, OWNER_INDEX_KEY, &true).await
+    }
+
+    async fn handle(&mut self, mut req: Request) -> ApiResult<Response> {
+        let path = req.path();
+        let mut storage = self.state.storage();
+
+        match (req.method(), path.as_str()) {
+            (Method::Post, "/verify") => {
+                let KeyToken { token } = req.json().await.map_err(AppError::bad_request)?;
+                let invalid = || AppError::Unauthorized("Invalid API key".to_string());
+
+                let (id, secret) = parse_token(&token).ok_or_else(invalid)?;
+                let stored = self.get_key(id).await.map_err(|_| invalid())?;
+                if !auth::constant_time_eq(hash_secret(secret).as_bytes(), stored.hash.as_bytes()) {
+                    return Err(invalid());
+                }
+
+                Ok(Response::from_json(&stored.key)?)
+            }
+            (Method::Get, "/keys") => {
+                let owner = requester(&req)?;
+
+                Ok(Response::from_json(&self.owner_keys(&owner).await?)?)
+            }
+            (Method::Post, "/keys") => {
+                let owner = requester(&req)?;
+                let new_key = req
+                    .json::<NewApiKey>()
+                    .await
+                    .map_err(AppError::bad_request)?;
+                new_key.validate()?;
+
+                let mut id = [0u8; 8];
+                getrandom::getrandom(&mut id)
+                    .map_err(|error| AppError::Internal(error.to_string()))?;
+                let id = id
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>();
+                let secret = auth::random_token(32)?;
+
+                let key = ApiKey {
+                    id,
+                    name: new_key.name,
+                    owner,
+                    scopes: new_key.scopes,
+                    created_at: Date::now().as_millis(),
+                };
+                let stored = StoredApiKey {
+                    key: key.clone(),
+                    hash: hash_secret(&secret),
+                };
+                storage::put(&mut storage, &key_key(&key.id), &stored).await?;
+                storage::put(&mut storage, &owner_key(&key.owner, &key.id), &key.id).await?;
+
+                let token = format!("{TOKEN_PREFIX}{}_{secret}", key.id);
+                Ok(Response::from_json(&MintedApiKey { key, token })?.with_status(201))
+            }
+            (_, "/keys") => Err(AppError::MethodNotAllowed("GET, POST")),
+            (method, path) => {
+                let Some(id) = path.strip_prefix("/keys/") else {
+                    return Err(AppError::NotFound(format!("No such path: {path}")));
+                };
+                let owner = requester(&req)?;
+
+                match method {
+                    Method::Patch => {
+                        let update = req
+                            .json::<ApiKeyUpdate>()
+                            .await
+                            .map_err(AppError::bad_request)?;
+                        update.validate()?;
+                        let mut stored = self.owned_key(id, &owner).await?;
+                        stored.key.scopes = update.scopes;
+                        storage::put(&mut storage, &key_key(id), &stored).await?;
+
+                        Ok(Response::from_json(&stored.key)?)
+                    }
+                    Method::Delete => {
+                        self.owned_key(id, &owner).await?;
+                        storage.delete(&key_key(id)).await?;
+                        storage.delete(&owner_key(&owner, id)).await?;
+
+                        Ok(Response::empty()?.with_status(204))
+                    }
+                    _ => Err(AppError::MethodNotAllowed("PATCH, DELETE")),
+                }
+            }
+        }
+    }
+}
+
+#[durable_object]
+impl DurableObject for ApiKeys {
+    fn new(state: State, env: Env) -> Self {
+        Self { env, state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        match self.handle(req).await {
+            Ok(response) => Ok(response),
+            Err(error) => error.into_worker_response(),
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{ApiResult, AppError};
+use crate::images::ImageMetadata;
+use crate::validation::{self, Validator};
+
+/// Name of the R2 bucket binding holding the attachments.
+pub const BUCKET: &str = "ATTACHMENTS";
+
+/// Storage key prefix of the records of the attachments posted with a
+/// message, followed by their id.
+pub const ATTACHMENT_PREFIX: &str = "attachment:";
+
+/// Storage key prefix of the records of the attachments uploaded but not
+/// posted yet, followed by their id.
+pub const UPLOAD_PREFIX: &str = "upload:";
+
+/// Size in bytes above which no room accepts an attachment.
+pub const MAX_ATTACHMENT_SIZE: usize = 25 * 1024 * 1024;
+
+/// Maximum number of attachments of a message.
+pub const MAX_ATTACHMENTS: usize = 10;
+
+/// Milliseconds after which an attachment not posted with any message is
+/// deleted.
+pub const PENDING_TTL: u64 = 24 * 60 * 60 * 1000;
+
+/// Custom metadata of the R2 objects naming the room they were uploaded to.
+pub const ROOM_METADATA: &str = "room";
+
+const MAX_NAME_LENGTH: usize = 255;
+
+const MAX_ALLOWED_TYPES: usize = 50;
+
+/// A file uploaded to a room, stored in R2 under [`object_key`].
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 hash of the content.
+    pub sha256: String,
+    pub uploaded_by: String,
+    /// Milliseconds since the Unix epoch.
+    pub uploaded_at: u64,
+    /// Set on images.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageMetadata>,
+    /// Id of the message the attachment was posted with, `None` until then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<u64>,
+}
+
+/// An upload as described to the room, once its content was received.
+#[derive(Serialize, Deserialize)]
+pub struct NewAttachment {
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageMetadata>,
+}
+
+impl NewAttachment {
+    pub fn new(name: &str, content_type: &str, content: &[u8]) -> Self {
+        NewAttachment {
+            name: name.to_string(),
+            content_type: essence(content_type),
+            size: content.len() as u64,
+            sha256: hex(&Sha256::digest(content)),
+            image: None,
+        }
+    }
+
+    /// Normalises the file name and checks the upload against the limits of
+    /// the room, failing with the invalid fields.
+    pub fn validate(self, limits: &AttachmentLimits) -> ApiResult<Self> {
+        let mut validator = Validator::default();
+
+        // Keep the last component of names sent with a path.
+        let name = validation::normalize(&self.name);
+        let name = name
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if name.is_empty() || name == "." || name == ".." {
+            validator.invalid("name", "must be a file name");
+        } else if name.chars().count() > MAX_NAME_LENGTH {
+            validator.invalid(
+                "name",
+                format!("must be at most {MAX_NAME_LENGTH} characters"),
+            );
+        }
+
+        if self.size == 0 {
+            validator.invalid("content", "must not be empty");
+        } else if self.size > limits.max_size {
+            validator.invalid(
+                "content",
+                format!("must be at most {} bytes in this room", limits.max_size),
+            );
+        }
+        let valid_type = self
+            .content_type
+            .split_once('/')
+            .is_some_and(|(kind, subtype)| is_token(kind) && is_token(subtype));
+        if !valid_type {
+            validator.invalid("content_type", "must be a MIME type");
+        } else if !limits.allows(&self.content_type) {
+            validator.invalid(
+                "content_type",
+                format!("{} is not allowed in this room", self.content_type),
+            );
+        }
+        validator.finish()?;
+
+        Ok(NewAttachment { name, ..self })
+    }
+}
+
+/// Limits of the attachments uploaded to a room.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AttachmentLimits {
+    /// Size in bytes, at most [`MAX_ATTACHMENT_SIZE`].
+    pub max_size: u64,
+    /// MIME types, or `type/*` wildcards, accepted in the room. Any type is
+    /// accepted if empty.
+    #[serde(default)]
+    pub allowed_types: Vec<String>,
+}
+
+impl Default for AttachmentLimits {
+    fn default() -> Self {
+        AttachmentLimits {
+            max_size: 10 * 1024 * 1024,
+            allowed_types: Vec::new(),
+        }
+    }
+}
+
+impl AttachmentLimits {
+    pub fn validate(&self) -> ApiResult<()> {
+        let mut validator = Validator::default();
+        if self.max_size == 0 || self.max_size > MAX_ATTACHMENT_SIZE as u64 {
+            validator.invalid(
+                "max_size",
+                format!("must be between 1 and {MAX_ATTACHMENT_SIZE} bytes"),
+            );
+        }
+        if self.allowed_types.len() > MAX_ALLOWED_TYPES {
+            validator.invalid(
+                "allowed_types",
+                format!("must hold at most {MAX_ALLOWED_TYPES} types"),
+            );
+        }
+        for (index, allowed) in self.allowed_types.iter().enumerate() {
+            let valid = allowed.split_once('/').is_some_and(|(kind, subtype)| {
+                is_token(kind) && (subtype == "*" || is_token(subtype))
+            });
+            if !valid {
+                validator.invalid(
+                    &format!("allowed_types[{index}]"),
+                    "must be a MIME type or a type/* wildcard",
+                );
+            }
+        }
+
+        validator.finish()
+    }
+
+    pub fn allows(&self, content_type: &str) -> bool {
+        let content_type = essence(content_type);
+
+        self.allowed_types.is_empty()
+            || self.allowed_types.iter().any(|allowed| {
+                let allowed = allowed.to_ascii_lowercase();
+                match allowed.strip_suffix("/*") {
+                    Some(kind) => content_type
+                        .split_once('/')
+                        .is_some_and(|(content_kind, _)| content_kind == kind),
+                    None => allowed == content_type,
+                }
+            })
+    }
+}
+
+/// The R2 key of an attachment.
+pub fn object_key(id: &str) -> String {
+    format!("attachments/{id}")
+}
+
+/// The R2 key of the thumbnail of an image attachment.
+pub fn thumbnail_key(id: &str) -> String {
+    format!("attachments/{id}/thumbnail")
+}
+
+pub fn attachment_key(id: &str) -> String {
+    format!("{ATTACHMENT_PREFIX}{id}")
+}
+
+pub fn upload_key(id: &str) -> String {
+    format!("{UPLOAD_PREFIX}{id}")
+}
+
+/// A random attachment id.
+pub fn new_id() -> ApiResult<String> {
+    let mut id = [0u8; 16];
+    getrandom::getrandom(&mut id).map_err(|error| AppError::Internal(error.to_string()))?;
+
+    Ok(hex(&id))
+}
+
+/// Whether `id` may be an attachment id, as generated by [`new_id`].
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A byte range of an attachment, both ends inclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Parses a `Range` header against a content of `size` bytes.
+///
+/// Only single ranges are served: headers in another unit, or listing
+/// several ranges, are ignored as RFC 9110 allows, and yield `Ok(None)`.
+/// Ranges starting past the end fail with 416.
+pub fn parse_range(header: &str, size: u64) -> ApiResult<Option<ByteRange>> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let unsatisfiable = || AppError::RangeNotSatisfiable { size };
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return Ok(None),
+        // The last `suffix` bytes.
+        ("", suffix) => {
+            let Ok(suffix) = suffix.parse::<u64>() else {
+                return Ok(None);
+            };
+            if suffix == 0 || size == 0 {
+                return Err(unsatisfiable());
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }
+        }
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return Ok(None),
+                },
+            };
+            if start >= size {
+                return Err(unsatisfiable());
+            }
+            ByteRange {
+                start,
+                end: end.min(size - 1),
+            }
+        }
+    };
+
+    Ok(Some(range))
+}
+
+/// A `Content-Disposition` header downloading the attachment under its name.
+pub fn content_disposition(name: &str) -> String {
+    let fallback = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// The lowercase `type/subtype` of a MIME type, without its parameters.
+pub fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Option<ByteRange> {
+        Some(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_bounded_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000).unwrap(), range(0, 99));
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000).unwrap(),
+            range(900, 999)
+        );
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000).unwrap(), range(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000).unwrap(), range(0, 999));
+        assert!(matches!(
+            parse_range("bytes=-0", 1000),
+            Err(AppError::RangeNotSatisfiable { size: 1000 })
+        ));
+    }
+
+    #[test]
+    fn parses_open_ended_ranges() {
+        assert_eq!(parse_range("bytes=500-", 1000).unwrap(), range(500, 999));
+    }
+
+    #[test]
+    fn rejects_ranges_starting_past_the_end() {
+        for header in ["bytes=1000-", "bytes=1000-1200", "bytes=5000-"] {
+            assert!(matches!(
+                parse_range(header, 1000),
+                Err(AppError::RangeNotSatisfiable { size: 1000 })
+            ));
+        }
+    }
+
+    #[test]
+    fn ignores_multiple_and_malformed_ranges() {
+        for header in [
+            "bytes=0-1,5-9",
+            "items=0-1",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=-",
+        ] {
+            assert_eq!(parse_range(header, 1000).unwrap(), None);
+        }
+    }
+}
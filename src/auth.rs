@@ -0,0 +1,465 @@
+//! Session tokens and the identity of API callers.
+//!
+//! Sessions are HS256 JSON Web Tokens signed with the `SESSION_SECRET` secret.
+//! The worker validates them and forwards the caller's name to durable objects
+//! in the [`USER_HEADER`], which objects trust as they are only reachable
+//! through the worker.
+//!
+//! Bots authenticate with API keys instead, see [`crate::api_keys`], which act
+//! on behalf of the user who minted them within the rooms they are scoped to.
+
+use std::sync::Arc;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use worker::send::SendFuture;
+
+use crate::api_keys::{Access, ApiKey, KeyScope, KeyToken, TOKEN_PREFIX};
+use crate::error::{ApiResult, AppError};
+use crate::AppState;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header carrying the authenticated user on requests to durable objects.
+pub const USER_HEADER: &str = "X-Chatroom-User";
+
+/// Header carrying the address of the room on requests to its durable object.
+pub const ROOM_HEADER: &str = "X-Chatroom-Room";
+
+/// Header set on WebSocket upgrades by callers whose API key may only read the
+/// room, so the socket refuses their messages.
+pub const READ_ONLY_HEADER: &str = "X-Chatroom-Read-Only";
+
+pub const SESSION_COOKIE: &str = "session";
+
+/// Lifetime of a session token, in seconds.
+pub const SESSION_TTL: u64 = 7 * 24 * 60 * 60;
+
+const PASSWORD_ITERATIONS: u32 = 100_000;
+
+/// Claims of a session token.
+#[derive(Serialize, Deserialize)]
+pub struct Claims {
+    /// Name of the user.
+    pub sub: String,
+    /// Issue and expiry times, in seconds since the Unix epoch.
+    pub iat: u64,
+    pub exp: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    pub expires_at: u64,
+}
+
+/// The authenticated caller of a route.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    /// Scopes of the API key the caller authenticated with, `None` for
+    /// sessions, which may act in every room.
+    pub scopes: Option<Vec<KeyScope>>,
+}
+
+impl User {
+    /// Checks that the caller may access `room`, as API keys are limited to
+    /// the rooms they are scoped to.
+    pub fn authorize(&self, room: &str, access: Access) -> ApiResult<()> {
+        let Some(scopes) = &self.scopes else {
+            return Ok(());
+        };
+
+        if scopes
+            .iter()
+            .any(|scope| (scope.room == "*" || scope.room == room) && scope.access >= access)
+        {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "API key has no {} access to room {room}",
+                match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                }
+            )))
+        }
+    }
+
+    /// Checks that the caller signed in themselves, rather than using an API
+    /// key, e.g. to manage their API keys.
+    pub fn require_session(&self) -> ApiResult<()> {
+        match self.scopes {
+            None => Ok(()),
+            Some(_) => Err(AppError::Forbidden(
+                "API keys cannot be used for this".to_string(),
+            )),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for User {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> ApiResult<Self> {
+        let token = bearer_token(&parts.headers)
+            .or_else(|| session_cookie(&parts.headers))
+            .ok_or_else(|| AppError::Unauthorized("Missing session token".to_string()))?;
+
+        if token.starts_with(TOKEN_PREFIX) {
+            let key = SendFuture::new(verify_api_key(state.env.clone(), token)).await?;
+            return Ok(User {
+                name: key.owner,
+                scopes: Some(key.scopes),
+            });
+        }
+
+        let claims = verify_token(&session_secret(&state.env)?, token, now())?;
+
+        Ok(User {
+            name: claims.sub,
+            scopes: None,
+        })
+    }
+}
+
+/// The caller of a route open to anonymous callers: `None` when no credentials
+/// were sent. Unlike `Option<User>`, invalid credentials, such as an expired
+/// token or a revoked API key, are still rejected.
+pub struct OptionalUser(pub Option<User>);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for OptionalUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> ApiResult<Self> {
+        if bearer_token(&parts.headers)
+            .or_else(|| session_cookie(&parts.headers))
+            .is_none()
+        {
+            return Ok(OptionalUser(None));
+        }
+
+        User::from_request_parts(parts, state)
+            .await
+            .map(|user| OptionalUser(Some(user)))
+    }
+}
+
+async fn verify_api_key(env: Arc<worker::Env>, token: &str) -> ApiResult<ApiKey> {
+    let json = serde_json::to_string(&KeyToken {
+        token: token.to_string(),
+    })
+    .map_err(|error| AppError::Internal(error.to_string()))?;
+    let response = crate::fetch_api_keys(
+        env,
+        crate::chatroom_request("/verify", worker::Method::Post, Some(json), None)?,
+    )
+    .await?;
+
+    crate::read_json(response).await
+}
+
+pub fn session_secret(env: &worker::Env) -> ApiResult<String> {
+    env.secret("SESSION_SECRET")
+        .map(|secret| secret.to_string())
+        .map_err(|_| AppError::Internal("SESSION_SECRET is not configured".to_string()))
+}
+
+/// The user a durable object request was made on behalf of, as forwarded by
+/// the worker.
+pub fn requester(req: &worker::Request) -> ApiResult<String> {
+    req.headers()
+        .get(USER_HEADER)?
+        .ok_or_else(|| AppError::Unauthorized("Authentication required".to_string()))
+}
+
+/// Whether the user may moderate every room, as listed in the comma-separated
+/// `MODERATORS` variable.
+pub fn is_moderator(env: &worker::Env, user: &str) -> bool {
+    env.var("MODERATORS").is_ok_and(|moderators| {
+        moderators
+            .to_string()
+            .split(',')
+            .any(|moderator| moderator.trim() == user)
+    })
+}
+
+/// Current time, in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    worker::Date::now().as_millis() / 1000
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    cookie(headers, SESSION_COOKIE)
+}
+
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let (cookie_name, value) = cookie.trim().split_once('=')?;
+            (cookie_name == name).then_some(value)
+        })
+}
+
+/// A `Set-Cookie` value storing `value` for `max_age` seconds, or clearing the
+/// cookie when `value` is `None`.
+pub fn cookie_header(name: &str, value: Option<&str>, max_age: u64) -> String {
+    match value {
+        Some(value) => {
+            format!("{name}={value}; Max-Age={max_age}; Path=/; HttpOnly; Secure; SameSite=Lax")
+        }
+        None => format!("{name}=; Max-Age=0; Path=/; HttpOnly; Secure; SameSite=Lax"),
+    }
+}
+
+/// The `Set-Cookie` value storing a session, or clearing it when `None`.
+pub fn session_cookie_header(session: Option<&Session>) -> String {
+    cookie_header(
+        SESSION_COOKIE,
+        session.map(|session| session.token.as_str()),
+        SESSION_TTL,
+    )
+}
+
+pub fn issue_token(secret: &str, name: &str, now: u64) -> ApiResult<Session> {
+    let claims = Claims {
+        sub: name.to_string(),
+        iat: now,
+        exp: now + SESSION_TTL,
+    };
+
+    Ok(Session {
+        token: sign(secret, &claims)?,
+        expires_at: claims.exp,
+    })
+}
+
+pub fn verify_token(secret: &str, token: &str, now: u64) -> ApiResult<Claims> {
+    let claims = verify::<Claims>(secret, token)?;
+    if claims.exp <= now {
+        return Err(AppError::Unauthorized("Session has expired".to_string()));
+    }
+
+    Ok(claims)
+}
+
+/// Signs the claims as an HS256 JSON Web Token.
+pub fn sign<T: Serialize>(secret: &str, claims: &T) -> ApiResult<String> {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(claims).map_err(|error| AppError::Internal(error.to_string()))?);
+    let signing_input = format!("{header}.{payload}");
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, &signing_input)?.finalize().into_bytes());
+
+    Ok(format!("{signing_input}.{signature}"))
+}
+
+/// Checks the signature of an HS256 JSON Web Token, returning its claims.
+/// Checking the expiry is left to the caller.
+pub fn verify<T: DeserializeOwned>(secret: &str, token: &str) -> ApiResult<T> {
+    let invalid = || AppError::Unauthorized("Invalid token".to_string());
+
+    let (signing_input, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+    let (header, payload) = signing_input.split_once('.').ok_or_else(invalid)?;
+
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    mac(secret, signing_input)?
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+
+    let header = URL_SAFE_NO_PAD.decode(header).map_err(|_| invalid())?;
+    let header = serde_json::from_slice::<serde_json::Value>(&header).map_err(|_| invalid())?;
+    if header["alg"] != "HS256" {
+        return Err(invalid());
+    }
+
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+    serde_json::from_slice(&payload).map_err(|_| invalid())
+}
+
+fn mac(secret: &str, input: &str) -> ApiResult<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .map_err(|error| AppError::Internal(error.to_string()))?;
+    mac.update(input.as_bytes());
+
+    Ok(mac)
+}
+
+/// Random bytes, base64url encoded.
+pub fn random_token(len: usize) -> ApiResult<String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).map_err(|error| AppError::Internal(error.to_string()))?;
+
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// User names are forwarded in headers and WebSocket tags, so they are kept
+/// short and plain. Names with an `@` are reserved for users signed in through
+/// the identity provider, see [`is_valid_account_name`].
+pub fn is_valid_user_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+' | '@'))
+}
+
+/// Whether a name may be registered with a password. Such names never
+/// contain an `@`, so they cannot shadow identity provider users.
+pub fn is_valid_account_name(name: &str) -> bool {
+    is_valid_user_name(name) && name.len() <= 32 && !name.contains('@')
+}
+
+/// A salted PBKDF2-HMAC-SHA256 password hash.
+#[derive(Serialize, Deserialize)]
+pub struct PasswordHash {
+    pub salt: String,
+    pub hash: String,
+    pub iterations: u32,
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> ApiResult<Self> {
+        let mut salt = [0u8; 16];
+        getrandom::getrandom(&mut salt).map_err(|error| AppError::Internal(error.to_string()))?;
+
+        Ok(PasswordHash {
+            salt: URL_SAFE_NO_PAD.encode(salt),
+            hash: URL_SAFE_NO_PAD.encode(derive(password, &salt, PASSWORD_ITERATIONS)),
+            iterations: PASSWORD_ITERATIONS,
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let (Ok(salt), Ok(expected)) = (
+            URL_SAFE_NO_PAD.decode(&self.salt),
+            URL_SAFE_NO_PAD.decode(&self.hash),
+        ) else {
+            return false;
+        };
+
+        constant_time_eq(&derive(password, &salt, self.iterations), &expected)
+    }
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut hash);
+
+    hash
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let session = issue_token(SECRET, "alice", NOW).unwrap();
+        assert_eq!(session.expires_at, NOW + SESSION_TTL);
+
+        let claims = verify_token(SECRET, &session.token, NOW + 60).unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.iat, NOW);
+        assert_eq!(claims.exp, NOW + SESSION_TTL);
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let session = issue_token(SECRET, "alice", NOW).unwrap();
+
+        assert!(matches!(
+            verify_token(SECRET, &session.token, NOW + SESSION_TTL),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_another_secret() {
+        let session = issue_token("another-secret", "alice", NOW).unwrap();
+
+        assert!(matches!(
+            verify_token(SECRET, &session.token, NOW),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let session = issue_token(SECRET, "alice", NOW).unwrap();
+        let (header, rest) = session.token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&Claims {
+                sub: "mallory".to_string(),
+                iat: NOW,
+                exp: NOW + SESSION_TTL,
+            })
+            .unwrap(),
+        );
+
+        assert!(matches!(
+            verify_token(SECRET, &format!("{header}.{forged}.{signature}"), NOW),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let claims = Claims {
+            sub: "alice".to_string(),
+            iat: NOW,
+            exp: NOW + SESSION_TTL,
+        };
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let signing_input = format!("{header}.{payload}");
+        let signature =
+            URL_SAFE_NO_PAD.encode(mac(SECRET, &signing_input).unwrap().finalize().into_bytes());
+
+        for token in [
+            format!("{signing_input}."),
+            format!("{signing_input}.{signature}"),
+            "not-a-token".to_string(),
+        ] {
+            assert!(matches!(
+                verify_token(SECRET, &token, NOW),
+                Err(AppError::Unauthorized(_))
+            ));
+        }
+    }
+}
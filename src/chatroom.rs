@@ -0,0 +1,2753 @@
+use std::collections::HashMap;
+
+use futures_channel::mpsc;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::acl::{AccessSettings, Acl, Invite, NewAcl, Role, RoleChange};
+use crate::attachments::{
+    self, attachment_key, object_key, thumbnail_key, upload_key, Attachment, AttachmentLimits,
+    NewAttachment, MAX_ATTACHMENTS, PENDING_TTL, UPLOAD_PREFIX,
+};
+use crate::auth::{self, requester, READ_ONLY_HEADER, ROOM_HEADER, USER_HEADER};
+use crate::directory::RoomVisibility;
+use crate::error::{ApiResult, AppError};
+use crate::global_search;
+use crate::moderation::{self, ContentRules, FlaggedMessage};
+use crate::presence::{
+    presence_key, ClientSignal, Presence, PRESENCE_PREFIX, PRESENCE_TTL, TYPING_TTL,
+};
+use crate::rate_limit::{Buckets, RateLimits, TokenBucket};
+use crate::retention::{
+    LegalHold, NewLegalHold, Retention, RetentionPolicy, CHECK_INTERVAL, PRUNE_BATCH,
+};
+use crate::search::{self, SearchHit, SearchIndex, SearchQuery, SearchResults};
+use crate::storage;
+use crate::validation::{self, InvalidParam, Validator};
+
+/// Storage key prefix of the messages. It is followed by the zero-padded id
+/// of the message, so listing returns messages in order.
+const MESSAGE_PREFIX: &str = "message:";
+const NEXT_SEQUENCE_KEY: &str = "next_sequence";
+const ACL_KEY: &str = "acl";
+const RATE_LIMITS_KEY: &str = "rate_limits";
+const CONTENT_RULES_KEY: &str = "content_rules";
+const ATTACHMENT_LIMITS_KEY: &str = "attachment_limits";
+const ROOM_NAME_KEY: &str = "room_name";
+const RETENTION_KEY: &str = "retention";
+const LEGAL_HOLD_KEY: &str = "legal_hold";
+
+/// Storage key prefix of the event log, followed by the zero-padded id of the
+/// event. It keeps the latest changes to the messages, which Server-Sent Events
+/// clients replay when reconnecting.
+const EVENT_PREFIX: &str = "event:";
+const NEXT_EVENT_KEY: &str = "next_event";
+
+/// Events kept in the event log.
+const EVENT_LOG_SIZE: u64 = 1000;
+
+/// Id of the next message to mirror to the search database, set while a
+/// backfill is running, or while a failed mirror waits to be retried.
+const BACKFILL_KEY: &str = "backfill_cursor";
+
+/// Messages mirrored by each run of a backfill.
+const BACKFILL_BATCH: usize = 100;
+
+/// Milliseconds before the alarm runs again after one of its jobs failed.
+const ALARM_RETRY_DELAY: u64 = 60_000;
+
+/// Storage key prefix of the thread index, followed by the zero-padded ids of
+/// the thread's root and of the reply.
+const THREAD_PREFIX: &str = "thread:";
+
+/// Storage key prefix of the moderation queue, followed by the zero-padded id
+/// of the flagged message.
+const FLAG_PREFIX: &str = "flag:";
+
+/// Version of the stored message schema, bumped whenever [`Message`] changes
+/// shape. Older records are upgraded by [`StoredMessage::upgrade`].
+const MESSAGE_VERSION: u32 = 7;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+/// Number of per-author rate limit buckets kept in memory.
+const MAX_AUTHOR_BUCKETS: usize = 1000;
+
+/// Author of messages posted before messages had authors.
+const ANONYMOUS: &str = "anonymous";
+
+/// A page of messages, oldest first.
+#[derive(Serialize, Deserialize)]
+pub struct MessageList {
+    pub messages: Vec<Message>,
+    /// Pass as `after` to fetch the next, newer, page.
+    #[serde(default)]
+    pub next_cursor: Option<u64>,
+    /// Pass as `before` to fetch the previous, older, page.
+    #[serde(default)]
+    pub prev_cursor: Option<u64>,
+}
+
+/// Query selecting a page of messages: up to `limit` messages between the
+/// `after` and `before` ids, both exclusive. Without `after`, the page holds
+/// the most recent messages of that range.
+struct PageQuery {
+    before: Option<u64>,
+    after: Option<u64>,
+    limit: usize,
+}
+
+impl PageQuery {
+    fn from_url(url: &Url) -> ApiResult<Self> {
+        let mut query = PageQuery {
+            before: None,
+            after: None,
+            limit: DEFAULT_PAGE_SIZE,
+        };
+
+        for (name, value) in url.query_pairs() {
+            let invalid = || AppError::BadRequest(format!("Invalid value for `{name}`: {value}"));
+            match name.as_ref() {
+                "before" => query.before = Some(value.parse().map_err(|_| invalid())?),
+                "after" => query.after = Some(value.parse().map_err(|_| invalid())?),
+                "limit" => match value.parse() {
+                    Ok(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => query.limit = limit,
+                    _ => return Err(invalid()),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(query)
+    }
+}
+
+/// A message as submitted by a client.
+#[derive(Serialize, Deserialize)]
+pub struct NewMessage {
+    pub content: String,
+    /// Client chosen token making retries idempotent: posting the same nonce
+    /// twice as the same author returns the original message.
+    pub nonce: Option<String>,
+    /// Id of the message replied to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    /// Ids of attachments uploaded to the room by the author. Messages with
+    /// attachments may have no content.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+}
+
+impl NewMessage {
+    /// Normalises the content, failing with the invalid fields.
+    fn validate(self, max_length: usize) -> ApiResult<Self> {
+        let mut validator = Validator::default();
+        let content = if self.content.trim().is_empty() && !self.attachments.is_empty() {
+            String::new()
+        } else {
+            validator.content("content", &self.content, max_length)
+        };
+        validator.nonce("nonce", self.nonce.as_deref());
+        if self.attachments.len() > MAX_ATTACHMENTS {
+            validator.invalid(
+                "attachments",
+                format!("must hold at most {MAX_ATTACHMENTS} attachments"),
+            );
+        }
+        validator.finish()?;
+
+        let mut attachments = self.attachments;
+        let mut seen = std::collections::HashSet::new();
+        attachments.retain(|id| seen.insert(id.clone()));
+
+        Ok(NewMessage {
+            content,
+            nonce: self.nonce,
+            reply_to: self.reply_to,
+            attachments,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub version: u32,
+    /// Server assigned id, increasing monotonically within a room.
+    pub id: u64,
+    pub author: String,
+    pub content: String,
+    /// Milliseconds since the Unix epoch, as seen by the server.
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    /// Earlier contents of the message, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<Edit>,
+    /// Set on tombstones, which keep the id and position of a deleted message
+    /// but none of its content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<u64>,
+    /// Id of the message replied to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    /// Id of the first message of the thread the message is a reply in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<u64>,
+    /// Number of replies in the thread started by the message, tombstones
+    /// included.
+    #[serde(default)]
+    pub reply_count: u32,
+    /// Reactions to the message, in the order they were first added.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Turns the message into a tombstone, returning its attachments for
+    /// them to be removed too.
+    fn delete(&mut self, now: u64) -> Vec<Attachment> {
+        self.content.clear();
+        self.edits.clear();
+        self.edited_at = None;
+        self.reactions.clear();
+        self.deleted_at = Some(now);
+
+        std::mem::take(&mut self.attachments)
+    }
+
+    /// Adds a user's reaction, returning whether they had not reacted so yet.
+    fn add_reaction(&mut self, emoji: &str, user: &str) -> bool {
+        let position = match self
+            .reactions
+            .iter()
+            .position(|reaction| reaction.emoji == emoji)
+        {
+            Some(position) => position,
+            None => {
+                self.reactions.push(Reaction {
+                    emoji: emoji.to_string(),
+                    count: 0,
+                    users: Vec::new(),
+                });
+                self.reactions.len() - 1
+            }
+        };
+
+        let reaction = &mut self.reactions[position];
+        if reaction.users.iter().any(|reacted| reacted == user) {
+            return false;
+        }
+        reaction.users.push(user.to_string());
+        reaction.count = reaction.users.len() as u32;
+
+        true
+    }
+
+    /// Removes a user's reaction, returning whether they had reacted so.
+    fn remove_reaction(&mut self, emoji: &str, user: &str) -> bool {
+        let Some(position) = self
+            .reactions
+            .iter()
+            .position(|reaction| reaction.emoji == emoji)
+        else {
+            return false;
+        };
+
+        let reaction = &mut self.reactions[position];
+        let Some(index) = reaction.users.iter().position(|reacted| reacted == user) else {
+            return false;
+        };
+        reaction.users.remove(index);
+        reaction.count = reaction.users.len() as u32;
+        if reaction.users.is_empty() {
+            self.reactions.remove(position);
+        }
+
+        true
+    }
+}
+
+/// The users who reacted to a message with the same emoji.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    /// A Unicode emoji, or a custom `:shortcode:`.
+    pub emoji: String,
+    pub count: u32,
+    pub users: Vec<String>,
+}
+
+/// A reaction as submitted by a client.
+#[derive(Serialize, Deserialize)]
+pub struct NewReaction {
+    pub emoji: String,
+}
+
+/// A replaced content of an edited message.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Edit {
+    pub content: String,
+    /// When the content was replaced.
+    pub edited_at: u64,
+}
+
+/// An edit as submitted by a client.
+#[derive(Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub content: String,
+}
+
+impl MessageEdit {
+    /// Normalises the content, failing with the invalid fields.
+    fn validate(self, max_length: usize) -> ApiResult<Self> {
+        let mut validator = Validator::default();
+        let content = validator.content("content", &self.content, max_length);
+        validator.finish()?;
+
+        Ok(MessageEdit { content })
+    }
+}
+
+/// An entry of the moderation queue.
+#[derive(Serialize, Deserialize)]
+pub struct QueuedMessage {
+    #[serde(flatten)]
+    pub flag: FlaggedMessage,
+    /// The message as it is now, `None` if it was pruned.
+    pub message: Option<Message>,
+}
+
+/// Selects the messages removed by a bulk deletion: the messages of `by`,
+/// posted between `since` and `until` (inclusive, in milliseconds since the
+/// Unix epoch). At least one of them must be given.
+struct DeletionFilter {
+    by: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl DeletionFilter {
+    fn from_url(url: &Url) -> ApiResult<Self> {
+        let mut filter = DeletionFilter {
+            by: None,
+            since: None,
+            until: None,
+        };
+
+        for (name, value) in url.query_pairs() {
+            let invalid = || AppError::BadRequest(format!("Invalid value for `{name}`: {value}"));
+            match name.as_ref() {
+                "by" => filter.by = Some(value.to_string()),
+                "since" => filter.since = Some(value.parse().map_err(|_| invalid())?),
+                "until" => filter.until = Some(value.parse().map_err(|_| invalid())?),
+                _ => {}
+            }
+        }
+
+        if filter.by.is_none() && filter.since.is_none() && filter.until.is_none() {
+            return Err(AppError::BadRequest(
+                "A bulk deletion needs at least one of `by`, `since` and `until`".to_string(),
+            ));
+        }
+
+        Ok(filter)
+    }
+
+    fn matches(&self, message: &Message) -> bool {
+        self.by.as_ref().is_none_or(|by| &message.author == by)
+            && self.since.is_none_or(|since| message.timestamp >= since)
+            && self.until.is_none_or(|until| message.timestamp <= until)
+    }
+}
+
+/// Any version of a message found in storage.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredMessage {
+    Current(Box<Message>),
+    /// The original schema, which only had the content. Such messages predate
+    /// ids and are keyed by their position in the room.
+    V1 {
+        content: String,
+    },
+}
+
+impl StoredMessage {
+    /// Upgrades the record to the current schema, returning whether it changed.
+    fn upgrade(self, id: u64) -> (Message, bool) {
+        match self {
+            // Versions 3 to 7 added the edit history, the deletion time, the
+            // thread fields, the reactions and the attachments, which default
+            // to empty.
+            StoredMessage::Current(mut message) if message.version < MESSAGE_VERSION => {
+                message.version = MESSAGE_VERSION;
+                (*message, true)
+            }
+            StoredMessage::Current(message) => (*message, false),
+            StoredMessage::V1 { content } => (
+                Message {
+                    version: MESSAGE_VERSION,
+                    id,
+                    author: ANONYMOUS.to_string(),
+                    content,
+                    timestamp: 0,
+                    nonce: None,
+                    edited_at: None,
+                    edits: Vec::new(),
+                    deleted_at: None,
+                    reply_to: None,
+                    thread_id: None,
+                    reply_count: 0,
+                    reactions: Vec::new(),
+                    attachments: Vec::new(),
+                },
+                true,
+            ),
+        }
+    }
+}
+
+/// Events pushed to the live subscribers of a room.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Message {
+        message: Message,
+    },
+    Edit {
+        message: Message,
+    },
+    Delete {
+        ids: Vec<u64>,
+    },
+    /// A user came online.
+    Join {
+        user: String,
+    },
+    /// A user went offline.
+    Leave {
+        user: String,
+    },
+    /// A user is typing, until the given time in milliseconds since the Unix
+    /// epoch unless renewed.
+    Typing {
+        user: String,
+        until: u64,
+    },
+    /// The reactions to message `id` changed.
+    Reaction {
+        id: u64,
+        reactions: Vec<Reaction>,
+    },
+    /// A reply was posted in the thread started by message `thread_id`.
+    Thread {
+        thread_id: u64,
+        reply_count: u32,
+        reply_id: u64,
+    },
+    Error {
+        error: String,
+    },
+}
+
+impl Event {
+    /// Name of the event, as used for the `event:` field of Server-Sent Events.
+    /// Joins, leaves and typing share the `presence` name, and are told apart
+    /// by the `type` of their data.
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Message { .. } => "message",
+            Event::Edit { .. } => "edit",
+            Event::Delete { .. } => "delete",
+            Event::Join { .. } | Event::Leave { .. } | Event::Typing { .. } => "presence",
+            Event::Reaction { .. } => "reaction",
+            Event::Thread { .. } => "thread",
+            Event::Error { .. } => "error",
+        }
+    }
+
+    /// Formats the event as a Server-Sent Events frame. Events from the event
+    /// log carry their `id`, so `Last-Event-ID` names the last one seen.
+    fn to_sse(&self, id: Option<u64>) -> Result<Vec<u8>> {
+        let mut frame = String::new();
+        if let Some(id) = id {
+            frame.push_str(&format!("id: {id}\n"));
+        }
+        frame.push_str(&format!(
+            "event: {}\ndata: {}\n\n",
+            self.name(),
+            serde_json::to_string(self)?
+        ));
+
+        Ok(frame.into_bytes())
+    }
+}
+
+type EventSender = mpsc::UnboundedSender<Result<Vec<u8>>>;
+
+/// An open Server-Sent Events stream, along with the user who opened it, if
+/// signed in.
+struct EventStream {
+    user: Option<String>,
+    sender: EventSender,
+}
+
+/// Prefix of the WebSocket tag naming the user who opened the socket.
+const USER_TAG_PREFIX: &str = "user:";
+
+/// Tag of the WebSockets opened with an API key that may only read the room.
+const READ_ONLY_TAG: &str = "read-only";
+
+fn websocket_tag(user: &str) -> String {
+    format!("{USER_TAG_PREFIX}{user}")
+}
+
+fn thread_prefix(thread_id: u64) -> String {
+    format!("{THREAD_PREFIX}{thread_id:020}:")
+}
+
+fn thread_key(thread_id: u64, id: u64) -> String {
+    format!("{}{id:020}", thread_prefix(thread_id))
+}
+
+fn flag_key(id: u64) -> String {
+    format!("{FLAG_PREFIX}{id:020}")
+}
+
+fn event_key(id: u64) -> String {
+    format!("{EVENT_PREFIX}{id:020}")
+}
+
+fn message_key(id: u64) -> String {
+    format!("{MESSAGE_PREFIX}{id:020}")
+}
+
+fn message_id(key: &str) -> Option<u64> {
+    key.strip_prefix(MESSAGE_PREFIX)?.parse().ok()
+}
+
+/// Lists stored messages, upgrading records written with an older schema.
+async fn list_messages(storage: &mut Storage, options: ListOptions<'_>) -> Result<Vec<Message>> {
+    let stored = storage::list::<StoredMessage>(storage, options).await?;
+
+    let mut messages = Vec::with_capacity(stored.len());
+    for (key, stored) in stored {
+        let Some(id) = message_id(&key) else {
+            continue;
+        };
+
+        let (message, upgraded) = stored.upgrade(id);
+        if upgraded {
+            storage::put(storage, &key, &message).await?;
+        }
+        messages.push(message);
+    }
+
+    Ok(messages)
+}
+
+#[allow(dead_code)]
+#[durable_object]
+pub struct Chatroom {
+    /// Cache of the persisted messages, loaded on first use.
+    messages: Option<Vec<Message>>,
+    next_sequence: u64,
+    /// Id of the next event of the event log, loaded on first use.
+    next_event: Option<u64>,
+    /// Open Server-Sent Events streams. Unlike WebSockets these cannot
+    /// hibernate, so they keep the object in memory while connected.
+    event_streams: Vec<EventStream>,
+    /// Cache of the room's ACL, `None` until loaded. Rooms without an ACL are
+    /// cached as `Some(None)`.
+    acl: Option<Option<Acl>>,
+    /// Cache of the room's rate limits, loaded on first use.
+    rate_limits: Option<RateLimits>,
+    /// Token buckets of the room's authors and of the room as a whole. They
+    /// are only kept in memory, and start out full when the object is evicted.
+    author_buckets: Buckets,
+    room_bucket: Option<TokenBucket>,
+    /// Search index of the messages, built on the first search and then kept
+    /// up to date as messages are posted, edited and deleted.
+    index: Option<SearchIndex>,
+    /// Address of the room, as sent by the worker, `None` until known.
+    name: Option<String>,
+    /// Whether the access settings were mirrored since the object started.
+    /// Searches treat rooms missing from the mirror as private, so this
+    /// happens before any message is mirrored.
+    acl_mirrored: bool,
+    env: Env,
+    state: State,
+}
+
+impl Chatroom {
+    async fn messages(&mut self) -> Result<&mut Vec<Message>> {
+        if self.messages.is_none() {
+            let mut storage = self.state.storage();
+            let messages =
+                list_messages(&mut storage, ListOptions::new().prefix(MESSAGE_PREFIX)).await?;
+
+            self.next_sequence = storage::get(&storage, NEXT_SEQUENCE_KEY)
+                .await?
+                .unwrap_or(0);
+            self.messages = Some(messages);
+        }
+
+        Ok(self.messages.get_or_insert_with(Vec::new))
+    }
+
+    async fn get_message(&self, id: u64) -> Result<Option<Message>> {
+        let stored = storage::get::<StoredMessage>(&self.state.storage(), &message_key(id)).await?;
+
+        Ok(stored.map(|stored| stored.upgrade(id).0))
+    }
+
+    /// Lists a page of messages straight from storage, which keeps them ordered
+    /// by id. With a `thread_id`, the page holds replies in that thread, as
+    /// found through the thread index.
+    async fn list_page(
+        &mut self,
+        query: &PageQuery,
+        thread_id: Option<u64>,
+    ) -> Result<MessageList> {
+        let prefix = match thread_id {
+            Some(thread_id) => thread_prefix(thread_id),
+            None => MESSAGE_PREFIX.to_string(),
+        };
+        let key = |id: u64| format!("{prefix}{id:020}");
+
+        let newest_first = query.after.is_none() || query.before.is_some();
+        let start = query.after.map(|after| key(after.saturating_add(1)));
+        let end = query.before.map(key);
+
+        let mut options = ListOptions::new()
+            .prefix(&prefix)
+            .reverse(newest_first)
+            .limit(query.limit + 1);
+        if let Some(start) = &start {
+            options = options.start(start);
+        }
+        if let Some(end) = &end {
+            options = options.end(end);
+        }
+
+        let mut messages = match thread_id {
+            None => list_messages(&mut self.state.storage(), options).await?,
+            Some(_) => {
+                let ids = storage::list::<u64>(&self.state.storage(), options).await?;
+                let mut messages = Vec::with_capacity(ids.len());
+                for (_, id) in ids {
+                    messages.extend(self.get_message(id).await?);
+                }
+                messages
+            }
+        };
+        let has_more = messages.len() > query.limit;
+        messages.truncate(query.limit);
+        if newest_first {
+            messages.reverse();
+        }
+
+        let (has_older, has_newer) = if newest_first {
+            (has_more, query.before.is_some())
+        } else {
+            (query.after.is_some(), has_more)
+        };
+
+        Ok(MessageList {
+            prev_cursor: messages
+                .first()
+                .filter(|_| has_older)
+                .map(|message| message.id),
+            next_cursor: messages
+                .last()
+                .filter(|_| has_newer)
+                .map(|message| message.id),
+            messages,
+        })
+    }
+
+    /// Validates a new message, then assigns it the next id and persists it. A
+    /// retried post, carrying a nonce already used by its author, returns the
+    /// original message instead. Replies are added to the thread of the message
+    /// they reply to.
+    async fn push_message(&mut self, new_message: NewMessage, author: &str) -> ApiResult<Message> {
+        Acl::can_post(self.acl().await?.as_ref(), author)?;
+        let new_message = new_message.validate(validation::max_message_length(&self.env))?;
+
+        if let Some(nonce) = &new_message.nonce {
+            let existing =
+                self.messages().await?.iter().rev().find(|message| {
+                    message.author == author && message.nonce.as_ref() == Some(nonce)
+                });
+            if let Some(existing) = existing {
+                return Ok(existing.clone());
+            }
+        }
+        let thread_id = match new_message.reply_to {
+            Some(reply_to) => Some(self.thread_of(reply_to).await?),
+            None => None,
+        };
+        let attachments = self
+            .pending_attachments(&new_message.attachments, author)
+            .await?;
+        self.throttle(author).await?;
+        let review = self.review(author, new_message.content).await?;
+        self.messages().await?;
+
+        let id = self.next_sequence;
+        let message = Message {
+            version: MESSAGE_VERSION,
+            id,
+            author: author.to_string(),
+            content: review.content,
+            timestamp: Date::now().as_millis(),
+            nonce: new_message.nonce,
+            edited_at: None,
+            edits: Vec::new(),
+            deleted_at: None,
+            reply_to: new_message.reply_to,
+            thread_id,
+            reply_count: 0,
+            reactions: Vec::new(),
+            attachments: attachments
+                .into_iter()
+                .map(|attachment| Attachment {
+                    message_id: Some(id),
+                    ..attachment
+                })
+                .collect(),
+        };
+
+        let mut storage = self.state.storage();
+        storage::put(&mut storage, &message_key(message.id), &message).await?;
+        storage::put(&mut storage, NEXT_SEQUENCE_KEY, &(message.id + 1)).await?;
+        self.claim_attachments(&message).await?;
+
+        self.next_sequence = message.id + 1;
+        self.flag(&message, review.flags).await?;
+        self.messages().await?.push(message.clone());
+        self.reindex(message.id, None, Some(&message.content));
+        self.mirror(std::slice::from_ref(&message)).await;
+        self.publish(Event::Message {
+            message: message.clone(),
+        })
+        .await?;
+
+        if let Some(thread_id) = thread_id {
+            self.add_reply(thread_id, message.id).await?;
+        }
+
+        Ok(message)
+    }
+
+    /// The thread a reply to the message joins: the thread the message is
+    /// itself a reply in, or else the one it starts.
+    async fn thread_of(&self, reply_to: u64) -> ApiResult<u64> {
+        let invalid = |reason: &str| {
+            AppError::Validation(vec![InvalidParam {
+                name: "reply_to".to_string(),
+                reason: reason.to_string(),
+            }])
+        };
+
+        match self.get_message(reply_to).await? {
+            None => Err(invalid("no such message")),
+            Some(parent) if parent.is_deleted() => Err(invalid("the message was deleted")),
+            Some(parent) => Ok(parent.thread_id.unwrap_or(parent.id)),
+        }
+    }
+
+    /// Indexes a reply under its thread and counts it on the thread's first
+    /// message.
+    async fn add_reply(&mut self, thread_id: u64, id: u64) -> Result<()> {
+        storage::put(&mut self.state.storage(), &thread_key(thread_id, id), &id).await?;
+
+        if let Some(mut root) = self.get_message(thread_id).await? {
+            root.reply_count += 1;
+            self.save_message(&root).await?;
+            self.broadcast(&Event::Thread {
+                thread_id,
+                reply_count: root.reply_count,
+                reply_id: id,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the content of a message, keeping the previous content in its
+    /// edit history. Only the author may edit a message, and only within the
+    /// `EDIT_WINDOW_SECONDS` after posting it when that variable is set.
+    async fn edit_message(
+        &mut self,
+        id: u64,
+        edit: MessageEdit,
+        requester: &str,
+    ) -> ApiResult<Message> {
+        let mut message = self
+            .get_message(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("No message with id {id}")))?;
+
+        if message.is_deleted() {
+            return Err(AppError::Conflict(format!("Message {id} was deleted")));
+        }
+
+        if message.author != requester {
+            return Err(AppError::Forbidden(
+                "Only the author may edit a message".to_string(),
+            ));
+        }
+        Acl::can_post(self.acl().await?.as_ref(), requester)?;
+        let edit = edit.validate(validation::max_message_length(&self.env))?;
+
+        let now = Date::now().as_millis();
+        if let Some(window) = self.edit_window() {
+            if now.saturating_sub(message.timestamp) > window {
+                return Err(AppError::Forbidden(
+                    "The edit window of the message has passed".to_string(),
+                ));
+            }
+        }
+
+        let review = self.review(requester, edit.content).await?;
+        self.flag(&message, review.flags).await?;
+
+        let previous = std::mem::replace(&mut message.content, review.content);
+        message.edits.push(Edit {
+            content: previous,
+            edited_at: now,
+        });
+        message.edited_at = Some(now);
+
+        self.save_message(&message).await?;
+        self.reindex(
+            id,
+            message.edits.last().map(|edit| edit.content.as_str()),
+            Some(&message.content),
+        );
+        self.mirror(std::slice::from_ref(&message)).await;
+        self.publish(Event::Edit {
+            message: message.clone(),
+        })
+        .await?;
+
+        Ok(message)
+    }
+
+    /// Adds or removes the requester's reaction to a message. Each user reacts
+    /// at most once with each emoji, so repeating either is a no-op.
+    async fn react(
+        &mut self,
+        id: u64,
+        reaction: NewReaction,
+        requester: &str,
+        add: bool,
+    ) -> ApiResult<Message> {
+        Acl::can_post(self.acl().await?.as_ref(), requester)?;
+        let mut validator = Validator::default();
+        validator.emoji("emoji", &reaction.emoji);
+        validator.finish()?;
+
+        let mut message = self
+            .get_message(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("No message with id {id}")))?;
+        if message.is_deleted() {
+            return Err(AppError::Conflict(format!("Message {id} was deleted")));
+        }
+
+        let changed = if add {
+            message.add_reaction(&reaction.emoji, requester)
+        } else {
+            message.remove_reaction(&reaction.emoji, requester)
+        };
+        if changed {
+            self.save_message(&message).await?;
+            self.broadcast(&Event::Reaction {
+                id,
+                reactions: message.reactions.clone(),
+            });
+        }
+
+        Ok(message)
+    }
+
+    /// Replaces a message by a tombstone. Authors may delete their own
+    /// messages, moderators any message.
+    async fn delete_message(&mut self, id: u64, requester: &str) -> ApiResult<Message> {
+        let mut message = self
+            .get_message(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("No message with id {id}")))?;
+
+        if message.author != requester && self.role(requester).await? < Some(Role::Moderator) {
+            return Err(AppError::Forbidden(
+                "Only the author or a moderator may delete a message".to_string(),
+            ));
+        }
+
+        if !message.is_deleted() {
+            let content = message.content.clone();
+            let attachments = message.delete(Date::now().as_millis());
+            self.save_message(&message).await?;
+            self.reindex(id, Some(&content), None);
+            self.mirror(std::slice::from_ref(&message)).await;
+            self.remove_attachments(&attachments).await?;
+            self.publish(Event::Delete { ids: vec![id] }).await?;
+        }
+
+        Ok(message)
+    }
+
+    /// Replaces every message matching the filter by a tombstone, returning
+    /// the ids of the deleted messages. Only moderators may do so.
+    async fn delete_messages(
+        &mut self,
+        filter: &DeletionFilter,
+        requester: &str,
+    ) -> ApiResult<Vec<u64>> {
+        if self.role(requester).await? < Some(Role::Moderator) {
+            return Err(AppError::Forbidden(
+                "Only moderators may delete messages in bulk".to_string(),
+            ));
+        }
+
+        let now = Date::now().as_millis();
+        let mut attachments = Vec::new();
+        let mut contents = Vec::new();
+        let deleted = self
+            .messages()
+            .await?
+            .iter()
+            .filter(|message| !message.is_deleted() && filter.matches(message))
+            .map(|message| {
+                let mut tombstone = message.clone();
+                contents.push((message.id, message.content.clone()));
+                attachments.extend(tombstone.delete(now));
+                tombstone
+            })
+            .collect::<Vec<_>>();
+
+        // The cache only takes the tombstones once they are persisted.
+        let mut storage = self.state.storage();
+        for message in &deleted {
+            storage::put(&mut storage, &message_key(message.id), message).await?;
+        }
+        let messages = self.messages().await?;
+        for tombstone in &deleted {
+            if let Ok(index) = messages.binary_search_by_key(&tombstone.id, |cached| cached.id) {
+                messages[index] = tombstone.clone();
+            }
+        }
+        for (id, content) in &contents {
+            self.reindex(*id, Some(content), None);
+        }
+
+        self.mirror(&deleted).await;
+        self.remove_attachments(&attachments).await?;
+
+        let ids = deleted.iter().map(|message| message.id).collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.publish(Event::Delete { ids: ids.clone() }).await?;
+        }
+
+        Ok(ids)
+    }
+
+    /// Updates the search index, if built, with the change of a message's
+    /// content.
+    fn reindex(&mut self, id: u64, old: Option<&str>, new: Option<&str>) {
+        let Some(index) = &mut self.index else {
+            return;
+        };
+
+        if let Some(old) = old {
+            index.remove(id, old);
+        }
+        if let Some(new) = new {
+            index.insert(id, new);
+        }
+    }
+
+    /// Searches the messages of the room, newest first.
+    async fn search(&mut self, query: &SearchQuery) -> Result<SearchResults> {
+        if self.index.is_none() {
+            let mut index = SearchIndex::default();
+            for message in self.messages().await?.iter() {
+                index.insert(message.id, &message.content);
+            }
+            self.index = Some(index);
+        }
+        let matching = self
+            .index
+            .as_ref()
+            .map(|index| index.matching(&query.terms))
+            .unwrap_or_default();
+
+        // The cache holds the messages in id order.
+        let messages = self.messages().await?;
+        let hits = matching
+            .into_iter()
+            .rev()
+            .filter_map(|id| {
+                let position = messages
+                    .binary_search_by_key(&id, |message| message.id)
+                    .ok()?;
+                Some(&messages[position])
+            })
+            .filter(|message| {
+                query.accepts(
+                    &message.author,
+                    message.timestamp,
+                    !message.attachments.is_empty(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let total = hits.len();
+        let mut page = hits
+            .into_iter()
+            .filter(|message| query.before.is_none_or(|before| message.id < before))
+            .take(query.limit + 1)
+            .collect::<Vec<_>>();
+        let has_more = page.len() > query.limit;
+        page.truncate(query.limit);
+
+        Ok(SearchResults {
+            next_cursor: page.last().filter(|_| has_more).map(|message| message.id),
+            hits: page
+                .into_iter()
+                .map(|message| SearchHit {
+                    message: message.clone(),
+                    snippet: search::snippet(&message.content, &query.terms),
+                })
+                .collect(),
+            total,
+        })
+    }
+
+    /// The address of the room, once sent by the worker.
+    async fn name(&mut self) -> Result<Option<String>> {
+        if self.name.is_none() {
+            self.name = storage::get(&self.state.storage(), ROOM_NAME_KEY).await?;
+        }
+
+        Ok(self.name.clone())
+    }
+
+    /// Records the address of the room, for the changes made outside of a
+    /// request, such as by alarms, to be mirrored.
+    async fn remember_name(&mut self, name: String) -> Result<()> {
+        if self.name().await?.as_ref() != Some(&name) {
+            storage::put(&mut self.state.storage(), ROOM_NAME_KEY, &name).await?;
+            self.name = Some(name);
+        }
+
+        Ok(())
+    }
+
+    /// Mirrors messages to the cross-room search database. Failures are
+    /// logged rather than failing the change, and the alarm retries them
+    /// through a backfill starting at the first of the messages.
+    async fn mirror(&mut self, messages: &[Message]) {
+        let Ok(db) = self.env.d1(global_search::DATABASE) else {
+            return;
+        };
+        let Ok(Some(room)) = self.name().await else {
+            return;
+        };
+
+        let mirrored = if !self.acl_mirrored && !self.mirror_acl().await {
+            false
+        } else if let Err(error) = global_search::mirror_messages(&db, &room, messages).await {
+            console_error!("Mirroring messages of {room} failed: {error}");
+            false
+        } else {
+            true
+        };
+
+        if let (false, Some(first)) = (mirrored, messages.first()) {
+            if let Err(error) = self.retry_mirror(Some(first.id)).await {
+                console_error!("Scheduling the mirror of {room} to be retried failed: {error}");
+            }
+        }
+    }
+
+    /// Mirrors the access settings of the room, see [`Chatroom::mirror`],
+    /// returning whether it succeeded. Failures are retried by the alarm.
+    async fn mirror_acl(&mut self) -> bool {
+        let Ok(db) = self.env.d1(global_search::DATABASE) else {
+            return false;
+        };
+        let (Ok(Some(room)), Ok(acl)) = (self.name().await, self.acl().await) else {
+            return false;
+        };
+
+        match global_search::mirror_acl(&db, &room, acl.as_ref()).await {
+            Ok(()) => self.acl_mirrored = true,
+            Err(error) => {
+                console_error!("Mirroring the access settings of {room} failed: {error}");
+                self.acl_mirrored = false;
+                if let Err(error) = self.retry_mirror(None).await {
+                    console_error!("Scheduling the mirror of {room} to be retried failed: {error}");
+                }
+            }
+        }
+
+        self.acl_mirrored
+    }
+
+    /// Has the alarm retry mirroring the room after a failure, by moving the
+    /// backfill back to message `from`, or to the next message to only mirror
+    /// the access settings. The backfill mirrors those first when they are
+    /// not mirrored yet.
+    async fn retry_mirror(&mut self, from: Option<u64>) -> Result<()> {
+        let from = match from {
+            Some(from) => from,
+            None => {
+                self.messages().await?;
+                self.next_sequence
+            }
+        };
+
+        let mut storage = self.state.storage();
+        let cursor = storage::get::<u64>(&storage, BACKFILL_KEY).await?;
+        if cursor.is_none_or(|cursor| from < cursor) {
+            storage::put(&mut storage, BACKFILL_KEY, &from).await?;
+        }
+
+        self.schedule_alarm(ALARM_RETRY_DELAY).await
+    }
+
+    /// Starts mirroring every message of the room to the search database, in
+    /// batches run by the alarm. Only global moderators may do so.
+    async fn start_backfill(&mut self, requester: &str) -> ApiResult<()> {
+        if !self.is_moderator(requester) {
+            return Err(AppError::Forbidden(
+                "Only global moderators may backfill the search index".to_string(),
+            ));
+        }
+
+        storage::put(&mut self.state.storage(), BACKFILL_KEY, &0u64).await?;
+        self.mirror_acl().await;
+        self.schedule_alarm(0).await?;
+
+        Ok(())
+    }
+
+    /// Mirrors the next batch of a running backfill.
+    async fn backfill(&mut self) -> Result<()> {
+        let mut storage = self.state.storage();
+        let Some(cursor) = storage::get::<u64>(&storage, BACKFILL_KEY).await? else {
+            return Ok(());
+        };
+        let (Ok(db), Some(room)) = (self.env.d1(global_search::DATABASE), self.name().await?)
+        else {
+            return storage.delete(BACKFILL_KEY).await.map(|_| ());
+        };
+
+        let start = message_key(cursor);
+        let batch = list_messages(
+            &mut storage,
+            ListOptions::new()
+                .prefix(MESSAGE_PREFIX)
+                .start(&start)
+                .limit(BACKFILL_BATCH),
+        )
+        .await?;
+        if !self.acl_mirrored {
+            global_search::mirror_acl(&db, &room, self.acl().await?.as_ref()).await?;
+            self.acl_mirrored = true;
+        }
+        global_search::mirror_messages(&db, &room, &batch).await?;
+
+        match batch.last() {
+            Some(last) if batch.len() == BACKFILL_BATCH => {
+                storage::put(&mut storage, BACKFILL_KEY, &(last.id + 1)).await?;
+                self.schedule_alarm(0).await
+            }
+            _ => storage.delete(BACKFILL_KEY).await.map(|_| ()),
+        }
+    }
+
+    /// Persists a changed message, updating the cache.
+    async fn save_message(&mut self, message: &Message) -> Result<()> {
+        storage::put(&mut self.state.storage(), &message_key(message.id), message).await?;
+
+        if let Some(cached) = self
+            .messages()
+            .await?
+            .iter_mut()
+            .find(|cached| cached.id == message.id)
+        {
+            *cached = message.clone();
+        }
+
+        Ok(())
+    }
+
+    /// The room's ACL, if it has one.
+    async fn acl(&mut self) -> Result<Option<Acl>> {
+        if self.acl.is_none() {
+            self.acl = Some(storage::get(&self.state.storage(), ACL_KEY).await?);
+        }
+
+        Ok(self.acl.clone().flatten())
+    }
+
+    async fn save_acl(&mut self, acl: Acl) -> Result<Acl> {
+        storage::put(&mut self.state.storage(), ACL_KEY, &acl).await?;
+        self.acl = Some(Some(acl.clone()));
+        self.mirror_acl().await;
+        self.update_directory(&acl).await;
+
+        Ok(acl)
+    }
+
+    /// Records whether the room is private, and who may read it, in the room
+    /// directory, which lists private rooms to their readers only. Failures
+    /// are logged rather than failing the change.
+    async fn update_directory(&mut self, acl: &Acl) {
+        let Ok(Some(room)) = self.name().await else {
+            return;
+        };
+        let visibility = RoomVisibility {
+            room: room.clone(),
+            private: acl.private,
+            readers: acl.readers(),
+        };
+
+        let result = async {
+            let json = serde_json::to_string(&visibility)
+                .map_err(|error| AppError::Internal(error.to_string()))?;
+            let response = crate::fetch_directory(
+                std::sync::Arc::new(self.env.clone()),
+                crate::chatroom_request("/rooms", Method::Patch, Some(json), None)?,
+            )
+            .await?;
+            if response.status_code() >= 400 {
+                return Err(crate::read_error(response).await);
+            }
+
+            Ok(())
+        };
+        if let Err(error) = result.await {
+            console_error!("Updating room {room} in the directory failed: {error}");
+        }
+    }
+
+    /// The user's role in the room. Users listed in the `MODERATORS` variable
+    /// moderate every room.
+    async fn role(&mut self, user: &str) -> Result<Option<Role>> {
+        let role = self.acl().await?.and_then(|acl| acl.role(user));
+        if self.is_moderator(user) {
+            return Ok(role.max(Some(Role::Moderator)));
+        }
+
+        Ok(role)
+    }
+
+    /// The room's ACL, when the requester has at least the given role.
+    async fn managed_acl(&mut self, requester: &str, role: Role) -> ApiResult<Acl> {
+        let acl = self.acl().await?.ok_or_else(|| {
+            AppError::Conflict("This room is open to everyone and has no members".to_string())
+        })?;
+        if self.role(requester).await? < Some(role) {
+            return Err(AppError::Forbidden(format!(
+                "This needs the {role} role or above"
+            )));
+        }
+
+        Ok(acl)
+    }
+
+    /// Checks that the requester outranks a member they are removing.
+    async fn outranks(&mut self, requester: &str, acl: &Acl, user: &str) -> ApiResult<()> {
+        if acl.role(user) >= self.role(requester).await? {
+            return Err(AppError::Forbidden(format!(
+                "{user} has the same or a higher role than you"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Gives a room created through the API its first owner. Rooms already in
+    /// use, such as ones addressed by name before being created, cannot be
+    /// claimed, as their owner could lock everyone else out.
+    async fn create_acl(&mut self, new_acl: NewAcl) -> ApiResult<Acl> {
+        if self.acl().await?.is_some() {
+            return Err(AppError::Conflict(
+                "The room already has an ACL".to_string(),
+            ));
+        }
+        if !self.messages().await?.is_empty()
+            || !self.state.get_websockets().is_empty()
+            || !self.presence().await?.is_empty()
+        {
+            return Err(AppError::Conflict("The room is already in use".to_string()));
+        }
+
+        Ok(self
+            .save_acl(Acl::new(&new_acl.owner, &new_acl.settings))
+            .await?)
+    }
+
+    async fn update_access(&mut self, settings: AccessSettings, requester: &str) -> ApiResult<Acl> {
+        let mut acl = self.managed_acl(requester, Role::Owner).await?;
+        if let Some(private) = settings.private {
+            acl.private = private;
+        }
+        if let Some(invite_only) = settings.invite_only {
+            acl.invite_only = invite_only;
+        }
+
+        let acl = self.save_acl(acl).await?;
+        self.disconnect_unauthorized("The room is now private");
+
+        Ok(acl)
+    }
+
+    async fn invite(&mut self, invite: Invite, requester: &str) -> ApiResult<Acl> {
+        let mut acl = self.managed_acl(requester, Role::Moderator).await?;
+        if acl.banned.contains(&invite.name) {
+            return Err(AppError::Conflict(format!("{} is banned", invite.name)));
+        }
+        if acl.role(&invite.name).is_some() {
+            return Err(AppError::Conflict(format!(
+                "{} is already a member",
+                invite.name
+            )));
+        }
+
+        acl.invited.insert(invite.name);
+
+        Ok(self.save_acl(acl).await?)
+    }
+
+    /// Makes the requester a member. Invite-only rooms require an invite.
+    async fn join(&mut self, requester: &str) -> ApiResult<Acl> {
+        let mut acl = self.acl().await?.ok_or_else(|| {
+            AppError::Conflict("This room is open to everyone and has no members".to_string())
+        })?;
+        if acl.role(requester).is_some() {
+            return Ok(acl);
+        }
+        if acl.banned.contains(requester) {
+            return Err(AppError::Forbidden(
+                "You are banned from this room".to_string(),
+            ));
+        }
+        if acl.invite_only && !acl.invited.contains(requester) {
+            return Err(AppError::Forbidden(
+                "This room can only be joined by invitation".to_string(),
+            ));
+        }
+
+        acl.invited.remove(requester);
+        acl.members.insert(requester.to_string(), Role::Member);
+
+        Ok(self.save_acl(acl).await?)
+    }
+
+    async fn leave(&mut self, requester: &str) -> ApiResult<()> {
+        let mut acl = self.acl().await?.unwrap_or_default();
+        if acl.role(requester).is_none() {
+            return Err(AppError::NotFound(
+                "You are not a member of this room".to_string(),
+            ));
+        }
+        if acl.is_last_owner(requester) {
+            return Err(AppError::Conflict(
+                "Make someone else an owner before leaving".to_string(),
+            ));
+        }
+
+        acl.members.remove(requester);
+        self.save_acl(acl).await?;
+        self.disconnect_unauthorized("Left the room");
+
+        Ok(())
+    }
+
+    /// Changes the role of a member. Only owners may do so.
+    async fn set_role(
+        &mut self,
+        user: &str,
+        change: RoleChange,
+        requester: &str,
+    ) -> ApiResult<Acl> {
+        let mut acl = self.managed_acl(requester, Role::Owner).await?;
+        if acl.role(user).is_none() {
+            return Err(AppError::NotFound(format!("{user} is not a member")));
+        }
+        if change.role != Role::Owner && acl.is_last_owner(user) {
+            return Err(AppError::Conflict(
+                "The room needs at least one owner".to_string(),
+            ));
+        }
+
+        acl.members.insert(user.to_string(), change.role);
+
+        Ok(self.save_acl(acl).await?)
+    }
+
+    /// Removes a member or withdraws an invite.
+    async fn kick(&mut self, user: &str, requester: &str) -> ApiResult<Acl> {
+        let mut acl = self.managed_acl(requester, Role::Moderator).await?;
+        self.outranks(requester, &acl, user).await?;
+        if acl.members.remove(user).is_none() && !acl.invited.remove(user) {
+            return Err(AppError::NotFound(format!("{user} is not a member")));
+        }
+
+        let acl = self.save_acl(acl).await?;
+        self.disconnect(user, "Removed from the room");
+
+        Ok(acl)
+    }
+
+    /// Removes a member and keeps them from joining again.
+    async fn ban(&mut self, user: &str, requester: &str) -> ApiResult<Acl> {
+        let mut acl = self.managed_acl(requester, Role::Moderator).await?;
+        self.outranks(requester, &acl, user).await?;
+
+        acl.members.remove(user);
+        acl.invited.remove(user);
+        acl.banned.insert(user.to_string());
+
+        let acl = self.save_acl(acl).await?;
+        self.disconnect(user, "Banned from the room");
+
+        Ok(acl)
+    }
+
+    async fn unban(&mut self, user: &str, requester: &str) -> ApiResult<Acl> {
+        let mut acl = self.managed_acl(requester, Role::Moderator).await?;
+        if !acl.banned.remove(user) {
+            return Err(AppError::NotFound(format!("{user} is not banned")));
+        }
+
+        Ok(self.save_acl(acl).await?)
+    }
+
+    /// Closes the WebSockets and event streams of a user who lost access to
+    /// the room.
+    fn disconnect(&mut self, user: &str, reason: &str) {
+        for ws in self.state.get_websockets_with_tag(&websocket_tag(user)) {
+            let _ = ws.close(Some(1008), Some(reason));
+        }
+        self.event_streams
+            .retain(|stream| stream.user.as_deref() != Some(user));
+    }
+
+    /// Closes the WebSockets and event streams of everyone who may no longer
+    /// read the room, anonymous readers included, once its ACL changed.
+    fn disconnect_unauthorized(&mut self, reason: &str) {
+        let acl = self.acl.clone().flatten();
+        for ws in self.state.get_websockets() {
+            let user = self.websocket_user(&ws);
+            if Acl::can_read(acl.as_ref(), user.as_deref()).is_err() {
+                let _ = ws.close(Some(1008), Some(reason));
+            }
+        }
+        self.event_streams
+            .retain(|stream| Acl::can_read(acl.as_ref(), stream.user.as_deref()).is_ok());
+    }
+
+    async fn rate_limits(&mut self) -> Result<RateLimits> {
+        if let Some(rate_limits) = self.rate_limits {
+            return Ok(rate_limits);
+        }
+
+        let rate_limits = storage::get(&self.state.storage(), RATE_LIMITS_KEY)
+            .await?
+            .unwrap_or_default();
+        self.rate_limits = Some(rate_limits);
+
+        Ok(rate_limits)
+    }
+
+    /// Replaces the room's rate limits. Moderators and owners may do so.
+    async fn set_rate_limits(
+        &mut self,
+        rate_limits: RateLimits,
+        requester: &str,
+    ) -> ApiResult<RateLimits> {
+        self.require_moderator(requester).await?;
+        rate_limits.validate()?;
+
+        storage::put(&mut self.state.storage(), RATE_LIMITS_KEY, &rate_limits).await?;
+        self.rate_limits = Some(rate_limits);
+
+        Ok(rate_limits)
+    }
+
+    /// Takes a token from the buckets of the author and of the room, failing
+    /// with 429 when either is empty.
+    async fn throttle(&mut self, author: &str) -> ApiResult<()> {
+        let limits = self.rate_limits().await?;
+        let now = Date::now().as_millis();
+
+        let author_bucket = self.author_buckets.get(author, limits.author, now);
+        author_bucket.check(limits.author, now)?;
+        let room_bucket = self
+            .room_bucket
+            .get_or_insert_with(|| TokenBucket::new(limits.room, now));
+        room_bucket.check(limits.room, now)?;
+
+        author_bucket.take();
+        room_bucket.take();
+
+        Ok(())
+    }
+
+    async fn content_rules(&self) -> Result<ContentRules> {
+        Ok(storage::get(&self.state.storage(), CONTENT_RULES_KEY)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Replaces the room's content rules. Moderators and owners may do so.
+    async fn set_content_rules(
+        &mut self,
+        rules: ContentRules,
+        requester: &str,
+    ) -> ApiResult<ContentRules> {
+        self.require_moderator(requester).await?;
+        rules.validate()?;
+
+        storage::put(&mut self.state.storage(), CONTENT_RULES_KEY, &rules).await?;
+
+        Ok(rules)
+    }
+
+    /// Runs content through the room's content policies.
+    async fn review(&self, author: &str, content: String) -> ApiResult<moderation::Review> {
+        let policies = moderation::policies(self.content_rules().await?)?;
+
+        moderation::review(&policies, author, content).await
+    }
+
+    /// Queues a message for review by the moderators, when it was flagged.
+    async fn flag(&mut self, message: &Message, reasons: Vec<String>) -> Result<()> {
+        if reasons.is_empty() {
+            return Ok(());
+        }
+
+        let flagged = FlaggedMessage {
+            message_id: message.id,
+            author: message.author.clone(),
+            reasons,
+            flagged_at: Date::now().as_millis(),
+        };
+
+        storage::put(&mut self.state.storage(), &flag_key(message.id), &flagged).await
+    }
+
+    /// The messages awaiting review, oldest first, along with their current
+    /// content.
+    async fn moderation_queue(&mut self, requester: &str) -> ApiResult<Vec<QueuedMessage>> {
+        self.require_moderator(requester).await?;
+
+        let flagged = storage::list::<FlaggedMessage>(
+            &self.state.storage(),
+            ListOptions::new().prefix(FLAG_PREFIX),
+        )
+        .await?;
+
+        let mut queue = Vec::with_capacity(flagged.len());
+        for (_, flagged) in flagged {
+            let message = self.get_message(flagged.message_id).await?;
+            queue.push(QueuedMessage {
+                flag: flagged,
+                message,
+            });
+        }
+
+        Ok(queue)
+    }
+
+    /// Removes a message from the moderation queue once reviewed.
+    async fn dismiss_flag(&mut self, id: u64, requester: &str) -> ApiResult<()> {
+        self.require_moderator(requester).await?;
+
+        if !self.state.storage().delete(&flag_key(id)).await? {
+            return Err(AppError::NotFound(format!("Message {id} is not flagged")));
+        }
+
+        Ok(())
+    }
+
+    async fn require_moderator(&mut self, requester: &str) -> ApiResult<()> {
+        if self.role(requester).await? < Some(Role::Moderator) {
+            return Err(AppError::Forbidden(
+                "Only moderators may do this".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn attachment_limits(&self) -> Result<AttachmentLimits> {
+        Ok(storage::get(&self.state.storage(), ATTACHMENT_LIMITS_KEY)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Replaces the attachment limits of the room. Only moderators may do so.
+    async fn set_attachment_limits(
+        &mut self,
+        limits: AttachmentLimits,
+        requester: &str,
+    ) -> ApiResult<AttachmentLimits> {
+        self.require_moderator(requester).await?;
+        limits.validate()?;
+
+        storage::put(&mut self.state.storage(), ATTACHMENT_LIMITS_KEY, &limits).await?;
+
+        Ok(limits)
+    }
+
+    /// Records an upload, once checked against the limits of the room. The
+    /// worker stores the content in R2 under the returned id, and the upload
+    /// is deleted unless posted with a message within [`PENDING_TTL`].
+    async fn upload(
+        &mut self,
+        new_attachment: NewAttachment,
+        author: &str,
+    ) -> ApiResult<Attachment> {
+        Acl::can_post(self.acl().await?.as_ref(), author)?;
+        let new_attachment = new_attachment.validate(&self.attachment_limits().await?)?;
+
+        let attachment = Attachment {
+            id: attachments::new_id()?,
+            name: new_attachment.name,
+            content_type: new_attachment.content_type,
+            size: new_attachment.size,
+            sha256: new_attachment.sha256,
+            image: new_attachment.image,
+            uploaded_by: author.to_string(),
+            uploaded_at: Date::now().as_millis(),
+            message_id: None,
+        };
+        storage::put(
+            &mut self.state.storage(),
+            &upload_key(&attachment.id),
+            &attachment,
+        )
+        .await?;
+        self.schedule_alarm(PENDING_TTL).await?;
+
+        Ok(attachment)
+    }
+
+    /// An attachment of the room. Uploads not posted yet are only found by
+    /// their uploader.
+    async fn attachment(&self, id: &str, requester: Option<&str>) -> ApiResult<Attachment> {
+        let storage = self.state.storage();
+        if let Some(attachment) = storage::get::<Attachment>(&storage, &attachment_key(id)).await? {
+            return Ok(attachment);
+        }
+
+        storage::get::<Attachment>(&storage, &upload_key(id))
+            .await?
+            .filter(|attachment| requester == Some(attachment.uploaded_by.as_str()))
+            .ok_or_else(|| AppError::NotFound(format!("No attachment with id {id}")))
+    }
+
+    /// The uploads of `author` with the given ids, which a new message is
+    /// about to be posted with.
+    async fn pending_attachments(
+        &self,
+        ids: &[String],
+        author: &str,
+    ) -> ApiResult<Vec<Attachment>> {
+        let storage = self.state.storage();
+        let mut pending = Vec::with_capacity(ids.len());
+        let mut validator = Validator::default();
+        for (index, id) in ids.iter().enumerate() {
+            let attachment = storage::get::<Attachment>(&storage, &upload_key(id))
+                .await?
+                .filter(|attachment| attachment.uploaded_by == author);
+            match attachment {
+                Some(attachment) => pending.push(attachment),
+                None => validator.invalid(
+                    &format!("attachments[{index}]"),
+                    "must be an attachment you uploaded to this room and did not post yet",
+                ),
+            }
+        }
+        validator.finish()?;
+
+        Ok(pending)
+    }
+
+    /// Moves the uploads posted with a message to the attachments of the room.
+    async fn claim_attachments(&mut self, message: &Message) -> Result<()> {
+        let mut storage = self.state.storage();
+        for attachment in &message.attachments {
+            storage::put(&mut storage, &attachment_key(&attachment.id), attachment).await?;
+            storage.delete(&upload_key(&attachment.id)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the content and records of attachments.
+    async fn remove_attachments(&mut self, removed: &[Attachment]) -> Result<()> {
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        let bucket = self.env.bucket(attachments::BUCKET)?;
+        let mut storage = self.state.storage();
+        for attachment in removed {
+            bucket.delete(object_key(&attachment.id)).await?;
+            if attachment.image.is_some() {
+                bucket.delete(thumbnail_key(&attachment.id)).await?;
+            }
+            storage.delete(&attachment_key(&attachment.id)).await?;
+            storage.delete(&upload_key(&attachment.id)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the uploads never posted with a message, and schedules the
+    /// next check while others are pending.
+    async fn prune_uploads(&mut self) -> Result<()> {
+        let now = Date::now().as_millis();
+        let uploads = storage::list::<Attachment>(
+            &self.state.storage(),
+            ListOptions::new().prefix(UPLOAD_PREFIX),
+        )
+        .await?;
+
+        let (expired, pending): (Vec<_>, Vec<_>) = uploads
+            .into_iter()
+            .map(|(_, attachment)| attachment)
+            .partition(|attachment| attachment.uploaded_at + PENDING_TTL <= now);
+        self.remove_attachments(&expired).await?;
+
+        if let Some(next_expiry) = pending
+            .iter()
+            .map(|attachment| attachment.uploaded_at + PENDING_TTL)
+            .min()
+        {
+            self.schedule_alarm(next_expiry.saturating_sub(now)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn retention(&self) -> Result<Retention> {
+        let storage = self.state.storage();
+
+        Ok(Retention {
+            policy: storage::get(&storage, RETENTION_KEY)
+                .await?
+                .unwrap_or_default(),
+            legal_hold: storage::get(&storage, LEGAL_HOLD_KEY).await?,
+        })
+    }
+
+    /// Replaces the retention policy of the room, pruning in the background
+    /// from then on. Only owners and global moderators may do so.
+    async fn set_retention(
+        &mut self,
+        policy: RetentionPolicy,
+        requester: &str,
+    ) -> ApiResult<Retention> {
+        if !self.is_moderator(requester) && self.role(requester).await? < Some(Role::Owner) {
+            return Err(AppError::Forbidden(
+                "Only owners may change the retention policy of a room".to_string(),
+            ));
+        }
+        policy.validate()?;
+
+        let mut storage = self.state.storage();
+        if policy.is_set() {
+            storage::put(&mut storage, RETENTION_KEY, &policy).await?;
+            self.schedule_alarm(0).await?;
+        } else {
+            storage.delete(RETENTION_KEY).await?;
+        }
+
+        Ok(self.retention().await?)
+    }
+
+    /// Suspends pruning until the hold is lifted. Only global moderators may
+    /// place or lift legal holds.
+    async fn place_legal_hold(
+        &mut self,
+        hold: NewLegalHold,
+        requester: &str,
+    ) -> ApiResult<Retention> {
+        self.require_legal_authority(requester)?;
+        let hold = hold.validate()?;
+
+        let hold = LegalHold {
+            reason: hold.reason,
+            placed_by: requester.to_string(),
+            placed_at: Date::now().as_millis(),
+        };
+        storage::put(&mut self.state.storage(), LEGAL_HOLD_KEY, &hold).await?;
+
+        Ok(self.retention().await?)
+    }
+
+    async fn lift_legal_hold(&mut self, requester: &str) -> ApiResult<Retention> {
+        self.require_legal_authority(requester)?;
+
+        if !self.state.storage().delete(LEGAL_HOLD_KEY).await? {
+            return Err(AppError::NotFound(
+                "This room is not under a legal hold".to_string(),
+            ));
+        }
+        let retention = self.retention().await?;
+        if retention.policy.is_set() {
+            self.schedule_alarm(0).await?;
+        }
+
+        Ok(retention)
+    }
+
+    fn require_legal_authority(&self, requester: &str) -> ApiResult<()> {
+        if !self.is_moderator(requester) {
+            return Err(AppError::Forbidden(
+                "Only global moderators may place or lift legal holds".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the next batch of messages expired under the retention policy,
+    /// unless the room is under a legal hold, and schedules the next run.
+    async fn enforce_retention(&mut self) -> Result<()> {
+        let retention = self.retention().await?;
+        if !retention.is_active() {
+            return Ok(());
+        }
+
+        let now = Date::now().as_millis();
+        let messages = self.messages().await?;
+        let expired = retention.expired(messages, now);
+        let pruned = messages[..expired.min(PRUNE_BATCH)].to_vec();
+        self.prune(pruned).await?;
+
+        if expired > PRUNE_BATCH {
+            self.schedule_alarm(0).await
+        } else {
+            self.schedule_alarm(CHECK_INTERVAL).await
+        }
+    }
+
+    /// Deletes messages for good, along with their attachments, thread index
+    /// entries and flags, and only then drops them from the cache. Unlike
+    /// deleting a message, this leaves no tombstone. A thread goes with its
+    /// first message, so replies to pruned messages are pruned too, while the
+    /// reply counts of the threads that stay are lowered.
+    async fn prune(&mut self, mut pruned: Vec<Message>) -> Result<()> {
+        if pruned.is_empty() {
+            return Ok(());
+        }
+
+        let mut storage = self.state.storage();
+        let roots = pruned
+            .iter()
+            .filter(|message| message.reply_count > 0)
+            .map(|message| message.id)
+            .collect::<Vec<_>>();
+        for root in roots {
+            let replies =
+                storage::list::<u64>(&storage, ListOptions::new().prefix(&thread_prefix(root)))
+                    .await?;
+            for (_, id) in replies {
+                if pruned.iter().any(|message| message.id == id) {
+                    continue;
+                }
+                if let Some(reply) = self.get_message(id).await? {
+                    pruned.push(reply);
+                }
+            }
+        }
+
+        let ids = pruned.iter().map(|message| message.id).collect::<Vec<_>>();
+        let mut attachments = Vec::new();
+        let mut removed_replies = HashMap::<u64, u32>::new();
+        for message in &pruned {
+            storage.delete(&message_key(message.id)).await?;
+            storage.delete(&flag_key(message.id)).await?;
+            if let Some(thread_id) = message.thread_id {
+                storage.delete(&thread_key(thread_id, message.id)).await?;
+                if !ids.contains(&thread_id) {
+                    *removed_replies.entry(thread_id).or_default() += 1;
+                }
+            }
+            attachments.extend(message.attachments.iter().cloned());
+            self.reindex(message.id, Some(&message.content), None);
+        }
+        self.remove_attachments(&attachments).await?;
+
+        for (thread_id, removed) in removed_replies {
+            if let Some(mut root) = self.get_message(thread_id).await? {
+                root.reply_count = root.reply_count.saturating_sub(removed);
+                self.save_message(&root).await?;
+            }
+        }
+        self.messages()
+            .await?
+            .retain(|message| !ids.contains(&message.id));
+
+        if let (Ok(db), Some(room)) = (self.env.d1(global_search::DATABASE), self.name().await?) {
+            if let Err(error) = global_search::remove_messages(&db, &room, &ids).await {
+                console_error!("Removing pruned messages of {room} failed: {error}");
+            }
+        }
+        self.publish(Event::Delete { ids }).await?;
+
+        Ok(())
+    }
+
+    /// Records a sign of life of a user, announcing them when they come
+    /// online. `connections` adjusts their count of open WebSockets.
+    async fn heartbeat(&mut self, user: &str, connections: i32) -> Result<Presence> {
+        let now = Date::now().as_millis();
+        let key = presence_key(user);
+        let mut storage = self.state.storage();
+
+        let existing = storage::get::<Presence>(&storage, &key)
+            .await?
+            .filter(|presence| presence.is_online(now));
+        let joined = existing.is_none();
+        let mut presence = existing.unwrap_or_else(|| Presence::new(user, now));
+        presence.last_seen = now;
+        presence.connections = presence.connections.saturating_add_signed(connections);
+        storage::put(&mut storage, &key, &presence).await?;
+
+        if joined {
+            self.broadcast(&Event::Join {
+                user: user.to_string(),
+            });
+        }
+        self.schedule_alarm(PRESENCE_TTL).await?;
+
+        Ok(presence)
+    }
+
+    /// Tells the room a user is typing, for the next few seconds.
+    async fn typing(&mut self, user: &str) -> Result<Presence> {
+        let mut presence = self.heartbeat(user, 0).await?;
+        let until = presence.last_seen + TYPING_TTL;
+        presence.typing_until = Some(until);
+        storage::put(&mut self.state.storage(), &presence_key(user), &presence).await?;
+
+        self.broadcast(&Event::Typing {
+            user: user.to_string(),
+            until,
+        });
+
+        Ok(presence)
+    }
+
+    /// Records a closed WebSocket, announcing the user left once their last
+    /// socket is closed.
+    async fn depart(&mut self, user: &str) -> Result<()> {
+        let key = presence_key(user);
+        let mut storage = self.state.storage();
+        let Some(mut presence) = storage::get::<Presence>(&storage, &key).await? else {
+            return Ok(());
+        };
+
+        presence.connections = presence.connections.saturating_sub(1);
+        if presence.connections > 0 {
+            return storage::put(&mut storage, &key, &presence).await;
+        }
+
+        storage.delete(&key).await?;
+        self.broadcast(&Event::Leave {
+            user: user.to_string(),
+        });
+
+        Ok(())
+    }
+
+    /// The users online in the room.
+    async fn presence(&self) -> Result<Vec<Presence>> {
+        let now = Date::now().as_millis();
+        let presence = storage::list::<Presence>(
+            &self.state.storage(),
+            ListOptions::new().prefix(PRESENCE_PREFIX),
+        )
+        .await?
+        .into_iter()
+        .map(|(_, mut presence)| {
+            if !presence.is_typing(now) {
+                presence.typing_until = None;
+            }
+            presence
+        })
+        .filter(|presence| presence.is_online(now))
+        .collect();
+
+        Ok(presence)
+    }
+
+    /// Drops the users without an open WebSocket whose heartbeats stopped,
+    /// announcing they left, and schedules the next check while any are
+    /// online. Counts of open WebSockets are first corrected from the
+    /// sockets actually open, in case a close went unnoticed.
+    async fn expire_presence(&mut self) -> Result<()> {
+        let now = Date::now().as_millis();
+        let mut storage = self.state.storage();
+        let records =
+            storage::list::<Presence>(&storage, ListOptions::new().prefix(PRESENCE_PREFIX)).await?;
+
+        let mut next_expiry = None::<u64>;
+        for (key, mut presence) in records {
+            let open = self
+                .state
+                .get_websockets_with_tag(&websocket_tag(&presence.user))
+                .len() as u32;
+            if presence.connections != open {
+                presence.connections = open;
+                storage::put(&mut storage, &key, &presence).await?;
+            }
+
+            if !presence.is_online(now) {
+                storage.delete(&key).await?;
+                self.broadcast(&Event::Leave {
+                    user: presence.user,
+                });
+            } else if presence.connections == 0 {
+                let expiry = presence.last_seen + PRESENCE_TTL + 1;
+                next_expiry = Some(next_expiry.map_or(expiry, |next| next.min(expiry)));
+            }
+        }
+
+        if let Some(next_expiry) = next_expiry {
+            self.schedule_alarm(next_expiry.saturating_sub(now)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Schedules the alarm in `delay` milliseconds, unless it is already due
+    /// sooner.
+    async fn schedule_alarm(&self, delay: u64) -> Result<()> {
+        let storage = self.state.storage();
+        let at = Date::now().as_millis() + delay;
+        if let Some(scheduled) = storage.get_alarm().await? {
+            if (scheduled as u64) <= at {
+                return Ok(());
+            }
+        }
+
+        storage
+            .set_alarm(std::time::Duration::from_millis(delay))
+            .await
+    }
+
+    /// Whether the user may moderate every room, as listed in the
+    /// comma-separated `MODERATORS` variable.
+    fn is_moderator(&self, user: &str) -> bool {
+        auth::is_moderator(&self.env, user)
+    }
+
+    /// Time after posting during which a message may be edited, in
+    /// milliseconds. Messages may always be edited when not configured.
+    fn edit_window(&self) -> Option<u64> {
+        let seconds = self.env.var("EDIT_WINDOW_SECONDS").ok()?.to_string();
+
+        seconds
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .map(|seconds| seconds * 1000)
+    }
+
+    /// Sends an event to every connected WebSocket and event stream. Failures
+    /// are ignored, a broken socket is cleaned up by the runtime when it
+    /// closes, and a closed event stream is dropped.
+    fn broadcast(&mut self, event: &Event) {
+        self.broadcast_with_id(event, None);
+    }
+
+    fn broadcast_with_id(&mut self, event: &Event, id: Option<u64>) {
+        for ws in self.state.get_websockets() {
+            let _ = ws.send(event);
+        }
+
+        if let Ok(frame) = event.to_sse(id) {
+            self.event_streams
+                .retain(|stream| stream.sender.unbounded_send(Ok(frame.clone())).is_ok());
+        }
+    }
+
+    /// Broadcasts a change to the messages, first appending it to the event log
+    /// so Server-Sent Events clients can replay it. The oldest event is dropped
+    /// once the log holds [`EVENT_LOG_SIZE`] events.
+    async fn publish(&mut self, event: Event) -> Result<()> {
+        let mut storage = self.state.storage();
+        let id = match self.next_event {
+            Some(id) => id,
+            None => storage::get(&storage, NEXT_EVENT_KEY).await?.unwrap_or(0),
+        };
+
+        storage::put(&mut storage, &event_key(id), &event).await?;
+        storage::put(&mut storage, NEXT_EVENT_KEY, &(id + 1)).await?;
+        if let Some(dropped) = id.checked_sub(EVENT_LOG_SIZE) {
+            storage.delete(&event_key(dropped)).await?;
+        }
+
+        self.next_event = Some(id + 1);
+        self.broadcast_with_id(&event, Some(id));
+
+        Ok(())
+    }
+
+    /// Opens a Server-Sent Events stream for `user`, first replaying the
+    /// logged events after `last_event_id` when the client is reconnecting.
+    async fn subscribe_events(
+        &mut self,
+        user: Option<String>,
+        last_event_id: Option<u64>,
+    ) -> Result<Response> {
+        let (sender, receiver) = mpsc::unbounded();
+
+        if let Some(last_event_id) = last_event_id {
+            let start = event_key(last_event_id + 1);
+            let missed = storage::list::<Event>(
+                &self.state.storage(),
+                ListOptions::new().prefix(EVENT_PREFIX).start(&start),
+            )
+            .await?;
+
+            for (key, event) in missed {
+                let id = key
+                    .strip_prefix(EVENT_PREFIX)
+                    .and_then(|id| id.parse().ok());
+                let _ = sender.unbounded_send(event.to_sse(id));
+            }
+        }
+        self.event_streams.push(EventStream { user, sender });
+
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "text/event-stream")?;
+        headers.set("Cache-Control", "no-cache")?;
+
+        Ok(Response::from_stream(receiver)?.with_headers(headers))
+    }
+
+    async fn handle(&mut self, mut req: worker::Request) -> ApiResult<Response> {
+        let path = req.path();
+
+        if path == "/" {
+            return Ok(Response::from_json(&None::<()>)?);
+        }
+
+        let user = req.headers().get(USER_HEADER)?;
+        if let Some(name) = req.headers().get(ROOM_HEADER)? {
+            self.remember_name(name).await?;
+        }
+
+        if path == "/messages" {
+            return match req.method() {
+                worker::Method::Get => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    let query = PageQuery::from_url(&req.url()?)?;
+                    Ok(Response::from_json(&self.list_page(&query, None).await?)?)
+                }
+                worker::Method::Post => {
+                    let include_history = req
+                        .url()?
+                        .query_pairs()
+                        .any(|(name, value)| name == "history" && value == "true");
+                    let body = req.text().await?;
+                    let new_message =
+                        serde_json::from_str::<NewMessage>(&body).map_err(AppError::bad_request)?;
+                    let message = self.push_message(new_message, &requester(&req)?).await?;
+
+                    if include_history {
+                        Ok(Response::from_json(&MessageList {
+                            messages: self.messages().await?.clone(),
+                            next_cursor: None,
+                            prev_cursor: None,
+                        })?)
+                    } else {
+                        Ok(Response::from_json(&message)?.with_status(201))
+                    }
+                }
+                worker::Method::Delete => {
+                    let filter = DeletionFilter::from_url(&req.url()?)?;
+                    let ids = self.delete_messages(&filter, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&serde_json::json!({ "deleted": ids }))?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, POST, DELETE")),
+            };
+        }
+
+        if let Some(id) = path
+            .strip_prefix("/messages/")
+            .and_then(|path| path.strip_suffix("/thread"))
+        {
+            let id = id
+                .parse::<u64>()
+                .map_err(|_| AppError::NotFound(format!("No message with id {id}")))?;
+            if !matches!(req.method(), worker::Method::Get) {
+                return Err(AppError::MethodNotAllowed("GET"));
+            }
+            Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+            if self.get_message(id).await?.is_none() {
+                return Err(AppError::NotFound(format!("No message with id {id}")));
+            }
+
+            let query = PageQuery::from_url(&req.url()?)?;
+            return Ok(Response::from_json(
+                &self.list_page(&query, Some(id)).await?,
+            )?);
+        }
+
+        if let Some(id) = path
+            .strip_prefix("/messages/")
+            .and_then(|path| path.strip_suffix("/reactions"))
+        {
+            let id = id
+                .parse::<u64>()
+                .map_err(|_| AppError::NotFound(format!("No message with id {id}")))?;
+            let add = match req.method() {
+                worker::Method::Post => true,
+                worker::Method::Delete => false,
+                _ => return Err(AppError::MethodNotAllowed("POST, DELETE")),
+            };
+            let reaction = req
+                .json::<NewReaction>()
+                .await
+                .map_err(AppError::bad_request)?;
+
+            let message = self.react(id, reaction, &requester(&req)?, add).await?;
+            return Ok(Response::from_json(&message)?);
+        }
+
+        if let Some(id) = path.strip_prefix("/messages/") {
+            let id = id
+                .parse::<u64>()
+                .map_err(|_| AppError::NotFound(format!("No message with id {id}")))?;
+
+            return match req.method() {
+                worker::Method::Get => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    match self.get_message(id).await? {
+                        Some(message) => Ok(Response::from_json(&message)?),
+                        None => Err(AppError::NotFound(format!("No message with id {id}"))),
+                    }
+                }
+                worker::Method::Patch => {
+                    let body = req.text().await?;
+                    let edit = serde_json::from_str::<MessageEdit>(&body)
+                        .map_err(AppError::bad_request)?;
+
+                    let message = self.edit_message(id, edit, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&message)?)
+                }
+                worker::Method::Delete => {
+                    let message = self.delete_message(id, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&message)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, PATCH, DELETE")),
+            };
+        }
+
+        if path == "/websocket" {
+            return match req.headers().get("Upgrade")?.as_deref() {
+                Some("websocket") => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    let read_only = req.headers().get(READ_ONLY_HEADER)?.is_some();
+                    let response = self.accept_websocket(user.as_deref(), read_only)?;
+                    if let Some(user) = &user {
+                        self.heartbeat(user, 1).await?;
+                    }
+
+                    Ok(response)
+                }
+                _ => Err(AppError::UpgradeRequired),
+            };
+        }
+
+        if path == "/attachments" {
+            if !matches!(req.method(), worker::Method::Post) {
+                return Err(AppError::MethodNotAllowed("POST"));
+            }
+            let new_attachment = req
+                .json::<NewAttachment>()
+                .await
+                .map_err(AppError::bad_request)?;
+            let attachment = self.upload(new_attachment, &requester(&req)?).await?;
+
+            return Ok(Response::from_json(&attachment)?.with_status(201));
+        }
+
+        if path == "/attachments/limits" {
+            return match req.method() {
+                worker::Method::Get => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    Ok(Response::from_json(&self.attachment_limits().await?)?)
+                }
+                worker::Method::Put => {
+                    let limits = req
+                        .json::<AttachmentLimits>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    let limits = self
+                        .set_attachment_limits(limits, &requester(&req)?)
+                        .await?;
+
+                    Ok(Response::from_json(&limits)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, PUT")),
+            };
+        }
+
+        if let Some(id) = path.strip_prefix("/attachments/") {
+            if !matches!(req.method(), worker::Method::Get) {
+                return Err(AppError::MethodNotAllowed("GET"));
+            }
+            Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+
+            let attachment = self.attachment(id, user.as_deref()).await?;
+            return Ok(Response::from_json(&attachment)?);
+        }
+
+        if path == "/presence" {
+            return match req.method() {
+                worker::Method::Get => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    Ok(Response::from_json(&self.presence().await?)?)
+                }
+                worker::Method::Post => {
+                    let requester = requester(&req)?;
+                    Acl::can_read(self.acl().await?.as_ref(), Some(&requester))?;
+
+                    Ok(Response::from_json(&self.heartbeat(&requester, 0).await?)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, POST")),
+            };
+        }
+
+        if path == "/typing" {
+            if !matches!(req.method(), worker::Method::Post) {
+                return Err(AppError::MethodNotAllowed("POST"));
+            }
+            let requester = requester(&req)?;
+            Acl::can_post(self.acl().await?.as_ref(), &requester)?;
+
+            return Ok(Response::from_json(&self.typing(&requester).await?)?);
+        }
+
+        if path == "/events" {
+            Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+            let last_event_id = req
+                .headers()
+                .get("Last-Event-ID")?
+                .and_then(|id| id.trim().parse().ok());
+
+            return Ok(self.subscribe_events(user, last_event_id).await?);
+        }
+
+        if path == "/search/backfill" {
+            if !matches!(req.method(), worker::Method::Post) {
+                return Err(AppError::MethodNotAllowed("POST"));
+            }
+
+            self.start_backfill(&requester(&req)?).await?;
+            return Ok(Response::empty()?.with_status(202));
+        }
+
+        if path == "/search" {
+            if !matches!(req.method(), worker::Method::Get) {
+                return Err(AppError::MethodNotAllowed("GET"));
+            }
+            Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+
+            let query = SearchQuery::from_url(&req.url()?)?;
+            return Ok(Response::from_json(&self.search(&query).await?)?);
+        }
+
+        if path == "/members" {
+            if !matches!(req.method(), worker::Method::Get) {
+                return Err(AppError::MethodNotAllowed("GET"));
+            }
+            let acl = self.acl().await?;
+            Acl::can_read(acl.as_ref(), user.as_deref())?;
+
+            return Ok(Response::from_json(&acl)?);
+        }
+
+        if path == "/access" {
+            return match req.method() {
+                worker::Method::Post => {
+                    let new_acl = req.json::<NewAcl>().await.map_err(AppError::bad_request)?;
+                    let acl = self.create_acl(new_acl).await?;
+
+                    Ok(Response::from_json(&acl)?.with_status(201))
+                }
+                worker::Method::Patch => {
+                    let settings = req
+                        .json::<AccessSettings>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    let acl = self.update_access(settings, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&acl)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("POST, PATCH")),
+            };
+        }
+
+        if path == "/retention" {
+            return match req.method() {
+                worker::Method::Get => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    Ok(Response::from_json(&self.retention().await?)?)
+                }
+                worker::Method::Put => {
+                    let policy = req
+                        .json::<RetentionPolicy>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    let retention = self.set_retention(policy, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&retention)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, PUT")),
+            };
+        }
+
+        if path == "/retention/hold" {
+            return match req.method() {
+                worker::Method::Put => {
+                    let hold = req
+                        .json::<NewLegalHold>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    let retention = self.place_legal_hold(hold, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&retention)?)
+                }
+                worker::Method::Delete => {
+                    let retention = self.lift_legal_hold(&requester(&req)?).await?;
+                    Ok(Response::from_json(&retention)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("PUT, DELETE")),
+            };
+        }
+
+        if path == "/rules" {
+            let requester = requester(&req)?;
+
+            return match req.method() {
+                worker::Method::Get => {
+                    self.require_moderator(&requester).await?;
+                    Ok(Response::from_json(&self.content_rules().await?)?)
+                }
+                worker::Method::Put => {
+                    let rules = req
+                        .json::<ContentRules>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    let rules = self.set_content_rules(rules, &requester).await?;
+
+                    Ok(Response::from_json(&rules)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, PUT")),
+            };
+        }
+
+        if path == "/moderation" {
+            if !matches!(req.method(), worker::Method::Get) {
+                return Err(AppError::MethodNotAllowed("GET"));
+            }
+
+            let queue = self.moderation_queue(&requester(&req)?).await?;
+            return Ok(Response::from_json(&queue)?);
+        }
+
+        if let Some(id) = path.strip_prefix("/moderation/") {
+            if !matches!(req.method(), worker::Method::Delete) {
+                return Err(AppError::MethodNotAllowed("DELETE"));
+            }
+            let id = id
+                .parse::<u64>()
+                .map_err(|_| AppError::NotFound(format!("Message {id} is not flagged")))?;
+
+            self.dismiss_flag(id, &requester(&req)?).await?;
+            return Ok(Response::empty()?.with_status(204));
+        }
+
+        if path == "/limits" {
+            return match req.method() {
+                worker::Method::Get => {
+                    Acl::can_read(self.acl().await?.as_ref(), user.as_deref())?;
+                    Ok(Response::from_json(&self.rate_limits().await?)?)
+                }
+                worker::Method::Put => {
+                    let rate_limits = req
+                        .json::<RateLimits>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    let rate_limits = self.set_rate_limits(rate_limits, &requester(&req)?).await?;
+
+                    Ok(Response::from_json(&rate_limits)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("GET, PUT")),
+            };
+        }
+
+        if let Some(action) = ["/invites", "/join", "/leave"]
+            .into_iter()
+            .find(|action| path == *action)
+        {
+            if !matches!(req.method(), worker::Method::Post) {
+                return Err(AppError::MethodNotAllowed("POST"));
+            }
+            let requester = requester(&req)?;
+
+            return match action {
+                "/invites" => {
+                    let invite = req.json::<Invite>().await.map_err(AppError::bad_request)?;
+                    Ok(Response::from_json(
+                        &self.invite(invite, &requester).await?,
+                    )?)
+                }
+                "/join" => Ok(Response::from_json(&self.join(&requester).await?)?),
+                _ => {
+                    self.leave(&requester).await?;
+                    Ok(Response::empty()?.with_status(204))
+                }
+            };
+        }
+
+        if let Some(member) = path.strip_prefix("/members/") {
+            let requester = requester(&req)?;
+
+            return match req.method() {
+                worker::Method::Patch => {
+                    let change = req
+                        .json::<RoleChange>()
+                        .await
+                        .map_err(AppError::bad_request)?;
+                    Ok(Response::from_json(
+                        &self.set_role(member, change, &requester).await?,
+                    )?)
+                }
+                worker::Method::Delete => {
+                    Ok(Response::from_json(&self.kick(member, &requester).await?)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("PATCH, DELETE")),
+            };
+        }
+
+        if let Some(banned) = path.strip_prefix("/bans/") {
+            let requester = requester(&req)?;
+
+            return match req.method() {
+                worker::Method::Put => {
+                    Ok(Response::from_json(&self.ban(banned, &requester).await?)?)
+                }
+                worker::Method::Delete => {
+                    Ok(Response::from_json(&self.unban(banned, &requester).await?)?)
+                }
+                _ => Err(AppError::MethodNotAllowed("PUT, DELETE")),
+            };
+        }
+
+        Err(AppError::NotFound(format!("No such path: {path}")))
+    }
+
+    /// Accepts a WebSocket through the hibernation API, so the room can be
+    /// evicted from memory while its sockets stay connected. The socket of an
+    /// authenticated user is tagged with the user's name, and may post unless
+    /// also tagged `read_only`.
+    fn accept_websocket(&self, user: Option<&str>, read_only: bool) -> Result<Response> {
+        let WebSocketPair { client, server } = WebSocketPair::new()?;
+        match user {
+            Some(user) => {
+                let tag = websocket_tag(user);
+                let tags = if read_only {
+                    vec![tag.as_str(), READ_ONLY_TAG]
+                } else {
+                    vec![tag.as_str()]
+                };
+                self.state.accept_websocket_with_tags(&server, &tags)
+            }
+            None => self.state.accept_web_socket(&server),
+        }
+
+        Response::from_websocket(client)
+    }
+
+    /// The user a WebSocket was opened by, if any.
+    fn websocket_user(&self, ws: &WebSocket) -> Option<String> {
+        self.state
+            .get_tags(ws)
+            .into_iter()
+            .find_map(|tag| tag.strip_prefix(USER_TAG_PREFIX).map(str::to_string))
+    }
+
+    /// Checks that a WebSocket may post to the room, as its user may and it was
+    /// not opened with a read-only API key.
+    async fn can_post(&mut self, ws: &WebSocket, user: &str) -> ApiResult<()> {
+        if self
+            .state
+            .get_tags(ws)
+            .iter()
+            .any(|tag| tag == READ_ONLY_TAG)
+        {
+            return Err(AppError::Forbidden(
+                "API key has no write access to the room".to_string(),
+            ));
+        }
+
+        Acl::can_post(self.acl().await?.as_ref(), user)
+    }
+}
+
+#[durable_object]
+impl DurableObject for Chatroom {
+    fn new(state: State, env: Env) -> Self {
+        Self {
+            messages: None,
+            next_sequence: 0,
+            next_event: None,
+            event_streams: Vec::new(),
+            acl: None,
+            rate_limits: None,
+            author_buckets: Buckets::new(MAX_AUTHOR_BUCKETS),
+            room_bucket: None,
+            index: None,
+            name: None,
+            acl_mirrored: false,
+            env,
+            state,
+        }
+    }
+
+    async fn fetch(&mut self, req: worker::Request) -> Result<Response> {
+        match self.handle(req).await {
+            Ok(response) => Ok(response),
+            Err(error) => error.into_worker_response(),
+        }
+    }
+
+    async fn websocket_message(
+        &mut self,
+        ws: WebSocket,
+        message: WebSocketIncomingMessage,
+    ) -> Result<()> {
+        let bytes = match message {
+            WebSocketIncomingMessage::String(text) => text.into_bytes(),
+            WebSocketIncomingMessage::Binary(bytes) => bytes,
+        };
+        let signal = serde_json::from_slice::<ClientSignal>(&bytes);
+
+        let Some(user) = self.websocket_user(&ws) else {
+            if signal.is_ok() {
+                return Ok(());
+            }
+            return ws.send(&Event::Error {
+                error: "Sign in to post messages".to_string(),
+            });
+        };
+
+        match signal {
+            Ok(ClientSignal::Heartbeat) => return self.heartbeat(&user, 0).await.map(|_| ()),
+            Ok(ClientSignal::Typing) => {
+                return match self.can_post(&ws, &user).await {
+                    Ok(()) => self.typing(&user).await.map(|_| ()),
+                    Err(error) => ws.send(&Event::Error {
+                        error: error.to_string(),
+                    }),
+                };
+            }
+            Err(_) => {
+                self.heartbeat(&user, 0).await?;
+            }
+        }
+
+        if let Err(error) = self.can_post(&ws, &user).await {
+            return ws.send(&Event::Error {
+                error: error.to_string(),
+            });
+        }
+
+        match serde_json::from_slice::<NewMessage>(&bytes) {
+            Ok(new_message) => match self.push_message(new_message, &user).await {
+                Ok(_) => Ok(()),
+                Err(error) => ws.send(&Event::Error {
+                    error: error.to_string(),
+                }),
+            },
+            Err(error) => ws.send(&Event::Error {
+                error: error.to_string(),
+            }),
+        }
+    }
+
+    async fn websocket_close(
+        &mut self,
+        ws: WebSocket,
+        _code: usize,
+        _reason: String,
+        _was_clean: bool,
+    ) -> Result<()> {
+        match self.websocket_user(&ws) {
+            Some(user) => self.depart(&user).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the room's background jobs. Each reschedules the alarm while it
+    /// has work left, and a failing job is retried after
+    /// [`ALARM_RETRY_DELAY`] without holding up the others.
+    async fn alarm(&mut self) -> Result<Response> {
+        let results = [
+            ("Expiring presence", self.expire_presence().await),
+            ("Pruning uploads", self.prune_uploads().await),
+            ("Backfilling the search index", self.backfill().await),
+            (
+                "Enforcing the retention policy",
+                self.enforce_retention().await,
+            ),
+        ];
+
+        let mut failed = false;
+        for (job, result) in results {
+            if let Err(error) = result {
+                console_error!("{job} failed: {error}");
+                failed = true;
+            }
+        }
+        if failed {
+            self.schedule_alarm(ALARM_RETRY_DELAY).await?;
+        }
+
+        Response::empty()
+    }
+
+    async fn websocket_error(&mut self, _ws: WebSocket, _error: Error) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::acl::AccessSettings;
+use crate::auth::USER_HEADER;
+use crate::error::{ApiResult, AppError};
+use crate::storage;
+
+/// Storage key prefix of the directory entries.
+const ROOM_PREFIX: &str = "room:";
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateRoom {
+    pub name: Option<String>,
+    /// Access settings of the room, whose creator becomes its owner.
+    #[serde(flatten)]
+    pub access: AccessSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoomInfo {
+    /// Hex id of the room's durable object.
+    pub id: String,
+    pub name: Option<String>,
+    /// Private rooms are only listed to those who may read them. Unknown for
+    /// rooms created before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<bool>,
+    /// Users who may read the room while private. Only kept by the directory,
+    /// which leaves them out of listings.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub readers: Vec<String>,
+    pub created_at: u64,
+}
+
+/// A change of the access settings of a listed room, sent by the room.
+#[derive(Serialize, Deserialize)]
+pub struct RoomVisibility {
+    pub room: String,
+    pub private: bool,
+    pub readers: Vec<String>,
+}
+
+impl RoomInfo {
+    /// The path segment the room is addressed by.
+    pub fn address(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Whether the room is listed to `user`. Rooms created before their
+    /// visibility was recorded are listed, for the caller to check.
+    fn is_listed_to(&self, user: Option<&str>) -> bool {
+        match self.private {
+            Some(true) => user.is_some_and(|user| self.readers.iter().any(|reader| reader == user)),
+            Some(false) | None => true,
+        }
+    }
+}
+
+/// Room names double as path segments, and must not be mistaken for the hex
+/// id of an unnamed room.
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !is_unique_id(name)
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether a room address is the hex id of a durable object.
+pub fn is_unique_id(room: &str) -> bool {
+    room.len() == 64 && room.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Directory of the rooms created through the API.
+#[allow(dead_code)]
+#[durable_object]
+pub struct RoomDirectory {
+    env: Env,
+    state: State,
+}
+
+impl RoomDirectory {
+    async fn handle(&mut self, mut req: Request) -> ApiResult<Response> {
+        if req.path() != "/rooms" {
+            return Err(AppError::NotFound(format!("No such path: {}", req.path())));
+        }
+
+        match req.method() {
+            // Private rooms are only listed to their readers.
+            Method::Get => {
+                let user = req.headers().get(USER_HEADER)?;
+                let mut rooms = storage::list::<RoomInfo>(
+                    &self.state.storage(),
+                    ListOptions::new().prefix(ROOM_PREFIX),
+                )
+                .await?
+                .into_iter()
+                .map(|(_, room)| room)
+                .filter(|room| room.is_listed_to(user.as_deref()))
+                .map(|room| RoomInfo {
+                    readers: Vec::new(),
+                    ..room
+                })
+                .collect::<Vec<_>>();
+                rooms.sort_by_key(|room| room.created_at);
+
+                Ok(Response::from_json(&rooms)?)
+            }
+            Method::Post => {
+                let room = req
+                    .json::<RoomInfo>()
+                    .await
+                    .map_err(AppError::bad_request)?;
+                let key = format!("{ROOM_PREFIX}{}", room.address());
+                let mut storage = self.state.storage();
+
+                if storage::get::<RoomInfo>(&storage, &key).await?.is_some() {
+                    return Err(AppError::Conflict(format!(
+                        "Room {} already exists",
+                        room.address()
+                    )));
+                }
+
+                storage::put(&mut storage, &key, &room).await?;
+
+                Ok(Response::from_json(&RoomInfo {
+                    readers: Vec::new(),
+                    ..room
+                })?)
+            }
+            // Rooms used by name without being created are not listed, and
+            // are left out.
+            Method::Patch => {
+                let visibility = req
+                    .json::<RoomVisibility>()
+                    .await
+                    .map_err(AppError::bad_request)?;
+                let key = format!("{ROOM_PREFIX}{}", visibility.room);
+                let mut storage = self.state.storage();
+
+                if let Some(mut room) = storage::get::<RoomInfo>(&storage, &key).await? {
+                    room.private = Some(visibility.private);
+                    room.readers = visibility.readers;
+                    storage::put(&mut storage, &key, &room).await?;
+                }
+
+                Ok(Response::empty()?.with_status(204))
+            }
+            _ => Err(AppError::MethodNotAllowed("GET, POST, PATCH")),
+        }
+    }
+}
+
+#[durable_object]
+impl DurableObject for RoomDirectory {
+    fn new(state: State, env: Env) -> Self {
+        Self { env, state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        match self.handle(req).await {
+            Ok(response) => Ok(response),
+            Err(error) => error.into_worker_response(),
+        }
+    }
+}
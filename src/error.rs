@@ -0,0 +1,275 @@
+use axum::extract::rejection::{BytesRejection, JsonRejection, PathRejection};
+use axum::http::{header, HeaderValue, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::validation::InvalidParam;
+
+pub type ApiResult<T> = std::result::Result<T, AppError>;
+
+/// Errors surfaced to API clients, rendered as RFC 7807 problem documents.
+///
+/// Durable objects answer with the same documents, so errors raised inside a
+/// room are forwarded to the client unchanged.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    /// Fields of the request that failed validation.
+    Validation(Vec<InvalidParam>),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// Carries the methods the resource does allow, for the `Allow` header.
+    MethodNotAllowed(&'static str),
+    Conflict(String),
+    PayloadTooLarge,
+    /// Carries the size of the content, for the `Content-Range` header.
+    RangeNotSatisfiable {
+        size: u64,
+    },
+    UpgradeRequired,
+    /// Carries the seconds to wait, for the `Retry-After` header.
+    TooManyRequests {
+        retry_after: u64,
+    },
+    Internal(String),
+    /// A durable object could not be reached or sent an unreadable response.
+    BadGateway(String),
+    /// An error response of a durable object.
+    Upstream(Box<Problem>),
+}
+
+impl AppError {
+    pub fn bad_request(error: impl std::fmt::Display) -> Self {
+        AppError::BadRequest(error.to_string())
+    }
+
+    pub fn bad_gateway(error: impl std::fmt::Display) -> Self {
+        AppError::BadGateway(error.to_string())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::RangeNotSatisfiable { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+            AppError::UpgradeRequired => StatusCode::UPGRADE_REQUIRED,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::Upstream(problem) => {
+                StatusCode::from_u16(problem.status).unwrap_or(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+
+    pub fn into_problem(self) -> Problem {
+        let status = self.status();
+        let allow = match &self {
+            AppError::MethodNotAllowed(allow) => Some(allow.to_string()),
+            _ => None,
+        };
+        let retry_after = match &self {
+            AppError::TooManyRequests { retry_after } => Some(*retry_after),
+            _ => None,
+        };
+        let invalid_params = match &self {
+            AppError::Validation(invalid_params) => invalid_params.clone(),
+            _ => Vec::new(),
+        };
+        let detail = match self {
+            AppError::Upstream(problem) => return *problem,
+            AppError::BadRequest(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Forbidden(detail)
+            | AppError::NotFound(detail)
+            | AppError::Conflict(detail)
+            | AppError::Internal(detail)
+            | AppError::BadGateway(detail) => Some(detail),
+            AppError::Validation(_) => Some("The request has invalid fields".to_string()),
+            AppError::TooManyRequests { retry_after } => Some(format!(
+                "Rate limit exceeded, retry in {retry_after} seconds"
+            )),
+            AppError::RangeNotSatisfiable { size } => {
+                Some(format!("The content is {size} bytes long"))
+            }
+            AppError::MethodNotAllowed(_)
+            | AppError::PayloadTooLarge
+            | AppError::UpgradeRequired => None,
+        };
+
+        Problem {
+            kind: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            allow,
+            retry_after,
+            invalid_params,
+        }
+    }
+
+    /// Renders the error as a durable object response.
+    pub fn into_worker_response(self) -> worker::Result<worker::Response> {
+        let problem = self.into_problem();
+
+        let mut response = worker::Response::from_json(&problem)?.with_status(problem.status);
+        response
+            .headers_mut()
+            .set("Content-Type", Problem::CONTENT_TYPE)?;
+        if let Some(allow) = &problem.allow {
+            response.headers_mut().set("Allow", allow)?;
+        }
+        if let Some(retry_after) = problem.retry_after {
+            response
+                .headers_mut()
+                .set("Retry-After", &retry_after.to_string())?;
+        }
+
+        Ok(response)
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::BadRequest(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Forbidden(detail)
+            | AppError::NotFound(detail)
+            | AppError::Conflict(detail)
+            | AppError::Internal(detail)
+            | AppError::BadGateway(detail) => write!(f, "{}: {detail}", self.status()),
+            AppError::Validation(invalid_params) => {
+                write!(f, "{}:", self.status())?;
+                for param in invalid_params {
+                    write!(f, " {} {};", param.name, param.reason)?;
+                }
+                Ok(())
+            }
+            AppError::Upstream(problem) => match &problem.detail {
+                Some(detail) => write!(f, "{}: {detail}", self.status()),
+                None => write!(f, "{}", self.status()),
+            },
+            _ => write!(f, "{}", self.status()),
+        }
+    }
+}
+
+impl From<worker::Error> for AppError {
+    fn from(error: worker::Error) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge
+        } else {
+            AppError::BadRequest(rejection.body_text())
+        }
+    }
+}
+
+impl From<BytesRejection> for AppError {
+    fn from(rejection: BytesRejection) -> Self {
+        if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge
+        } else {
+            AppError::BadRequest(rejection.body_text())
+        }
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        AppError::BadRequest(rejection.body_text())
+    }
+}
+
+impl axum::response::IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let content_range = match &self {
+            AppError::RangeNotSatisfiable { size } => Some(format!("bytes */{size}")),
+            _ => None,
+        };
+        let problem = self.into_problem();
+        let status = StatusCode::from_u16(problem.status).unwrap_or(StatusCode::BAD_GATEWAY);
+
+        let mut response = (status, axum::Json(&problem)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(Problem::CONTENT_TYPE),
+        );
+        if let Some(allow) = problem
+            .allow
+            .as_deref()
+            .and_then(|allow| HeaderValue::from_str(allow).ok())
+        {
+            response.headers_mut().insert(header::ALLOW, allow);
+        }
+        if let Some(retry_after) = problem.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        if let Some(content_range) =
+            content_range.and_then(|content_range| HeaderValue::from_str(&content_range).ok())
+        {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_RANGE, content_range);
+        }
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+
+        response
+    }
+}
+
+/// An RFC 7807 problem details document.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Methods allowed on the resource, for 405 responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<String>,
+    /// Seconds to wait before retrying, for 429 responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// Fields that failed validation, for 422 responses.
+    #[serde(
+        rename = "invalid-params",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub invalid_params: Vec<InvalidParam>,
+}
+
+impl Problem {
+    pub const CONTENT_TYPE: &'static str = "application/problem+json";
+}
+
+/// `axum::Json`, rejecting malformed bodies with an [`AppError`].
+#[derive(axum::extract::FromRequest, Default)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct Json<T>(pub T);
+
+/// `axum::extract::Path`, rejecting malformed parameters with an [`AppError`].
+#[derive(axum::extract::FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct Path<T>(pub T);
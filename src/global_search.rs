@@ -0,0 +1,336 @@
+//! Search across rooms, through a mirror of the messages in D1.
+//!
+//! Rooms write their messages and access settings to the database as they
+//! change, see `migrations/` for the schema. Messages written before the
+//! mirror existed are indexed by a backfill, which each room runs in batches
+//! from its alarm.
+
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::JsValue;
+use worker::{D1Database, D1PreparedStatement, Result};
+
+use crate::acl::Acl;
+use crate::chatroom::Message;
+use crate::error::{ApiResult, AppError};
+use crate::search::{self, Fragment};
+
+/// Name of the D1 database binding holding the mirror.
+pub const DATABASE: &str = "SEARCH_DB";
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Marks the start and end of the matches in the snippets built by SQLite.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+const SEARCH_SQL: &str = "
+SELECT m.room AS room, m.id AS id, m.author AS author, m.timestamp AS timestamp,
+  snippet(messages_fts, 0, char(2), char(3), '…', 24) AS snippet
+FROM messages_fts
+JOIN messages m ON m.rowid = messages_fts.rowid
+LEFT JOIN rooms r ON r.room = m.room
+WHERE messages_fts MATCH ?1
+  AND (?2 IS NULL OR m.author = ?2)
+  AND (?3 IS NULL OR m.room = ?3)
+  AND (?4 IS NULL OR m.timestamp >= ?4)
+  AND (?5 IS NULL OR m.timestamp <= ?5)
+  AND (?6 IS NULL OR m.has_attachment = ?6)
+  AND (?7 IS NULL OR m.room IN (SELECT value FROM json_each(?7)))
+  AND (?8 = 1 OR (
+    NOT EXISTS (
+      SELECT 1 FROM room_members b WHERE b.room = m.room AND b.user = ?9 AND b.banned = 1
+    )
+    AND (
+      r.private = 0
+      OR EXISTS (
+        SELECT 1 FROM room_members w WHERE w.room = m.room AND w.user = ?9 AND w.banned = 0
+      )
+    )
+  ))
+ORDER BY m.timestamp DESC, m.room, m.id DESC
+LIMIT ?10 OFFSET ?11";
+
+/// Writes messages to the mirror: live ones are inserted or updated, and
+/// tombstones removed.
+pub async fn mirror_messages(db: &D1Database, room: &str, messages: &[Message]) -> Result<()> {
+    let statements = messages
+        .iter()
+        .map(|message| {
+            if message.is_deleted() {
+                return db
+                    .prepare("DELETE FROM messages WHERE room = ?1 AND id = ?2")
+                    .bind(&[room.into(), number(message.id)]);
+            }
+
+            db.prepare(
+                "INSERT INTO messages (room, id, author, content, timestamp, has_attachment)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (room, id) DO UPDATE SET
+                   author = excluded.author,
+                   content = excluded.content,
+                   timestamp = excluded.timestamp,
+                   has_attachment = excluded.has_attachment",
+            )
+            .bind(&[
+                room.into(),
+                number(message.id),
+                message.author.as_str().into(),
+                message.content.as_str().into(),
+                number(message.timestamp),
+                number(!message.attachments.is_empty() as u64),
+            ])
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    batch(db, statements).await
+}
+
+/// Removes messages deleted for good, such as by a retention policy, from the
+/// mirror.
+pub async fn remove_messages(db: &D1Database, room: &str, ids: &[u64]) -> Result<()> {
+    let statements = ids
+        .iter()
+        .map(|id| {
+            db.prepare("DELETE FROM messages WHERE room = ?1 AND id = ?2")
+                .bind(&[room.into(), number(*id)])
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    batch(db, statements).await
+}
+
+/// Writes the access settings of a room to the mirror. Rooms without an ACL
+/// are open to everyone, while rooms missing from the mirror are searched as
+/// if private.
+pub async fn mirror_acl(db: &D1Database, room: &str, acl: Option<&Acl>) -> Result<()> {
+    let private = acl.is_some_and(|acl| acl.private);
+    let mut statements = vec![
+        db.prepare(
+            "INSERT INTO rooms (room, private) VALUES (?1, ?2)
+             ON CONFLICT (room) DO UPDATE SET private = excluded.private",
+        )
+        .bind(&[room.into(), number(private as u64)])?,
+        db.prepare("DELETE FROM room_members WHERE room = ?1")
+            .bind(&[room.into()])?,
+    ];
+
+    if let Some(acl) = acl {
+        let members = acl.members.keys().map(|user| (user, false));
+        let banned = acl.banned.iter().map(|user| (user, true));
+        for (user, banned) in members.chain(banned) {
+            statements.push(
+                db.prepare(
+                    "INSERT OR REPLACE INTO room_members (room, user, banned) VALUES (?1, ?2, ?3)",
+                )
+                .bind(&[
+                    room.into(),
+                    user.as_str().into(),
+                    number(banned as u64),
+                ])?,
+            );
+        }
+    }
+
+    batch(db, statements).await
+}
+
+async fn batch(db: &D1Database, statements: Vec<D1PreparedStatement>) -> Result<()> {
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    Ok(())
+}
+
+fn number(value: u64) -> JsValue {
+    JsValue::from_f64(value as f64)
+}
+
+/// A search across rooms: messages holding every word of `q`, each matched as
+/// a prefix, newest first. The other fields narrow the search.
+pub struct GlobalQuery {
+    pub terms: Vec<String>,
+    pub author: Option<String>,
+    pub room: Option<String>,
+    /// Milliseconds since the Unix epoch, inclusive.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub has_attachment: Option<bool>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl GlobalQuery {
+    pub fn from_query(query: Option<&str>) -> ApiResult<Self> {
+        let mut parsed = GlobalQuery {
+            terms: Vec::new(),
+            author: None,
+            room: None,
+            since: None,
+            until: None,
+            has_attachment: None,
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+        };
+
+        let pairs = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes());
+        for (name, value) in pairs {
+            let invalid = || AppError::BadRequest(format!("Invalid value for `{name}`: {value}"));
+            match name.as_ref() {
+                "q" => parsed.terms = search::terms(&value),
+                "author" => parsed.author = Some(value.to_string()),
+                "room" => parsed.room = Some(value.to_string()),
+                "since" => parsed.since = Some(value.parse().map_err(|_| invalid())?),
+                "until" => parsed.until = Some(value.parse().map_err(|_| invalid())?),
+                "has_attachment" => {
+                    parsed.has_attachment = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "offset" => parsed.offset = value.parse().map_err(|_| invalid())?,
+                "limit" => match value.parse() {
+                    Ok(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => parsed.limit = limit,
+                    _ => return Err(invalid()),
+                },
+                _ => {}
+            }
+        }
+
+        if parsed.terms.is_empty() {
+            return Err(AppError::BadRequest(
+                "A search needs a `q` holding at least one word".to_string(),
+            ));
+        }
+
+        Ok(parsed)
+    }
+
+    /// The FTS5 query matching every term as a prefix. Terms only hold letters
+    /// and digits, so quoting them is enough to escape them.
+    fn fts_query(&self) -> String {
+        self.terms
+            .iter()
+            .map(|term| format!("\"{term}\"*"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Whose messages a search may return.
+pub struct Reader<'a> {
+    /// The caller, `None` for anonymous callers, who only see rooms open to
+    /// everyone.
+    pub user: Option<&'a str>,
+    /// Whether the caller moderates every room, and so may read them all.
+    pub moderator: bool,
+    /// Rooms the caller's API key is scoped to, `None` if not limited.
+    pub rooms: Option<Vec<String>>,
+}
+
+/// A page of hits across rooms, newest first.
+#[derive(Serialize, Deserialize)]
+pub struct GlobalResults {
+    pub hits: Vec<GlobalHit>,
+    /// Pass as `offset` to fetch the next, older, page.
+    #[serde(default)]
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GlobalHit {
+    pub room: String,
+    pub message_id: u64,
+    pub author: String,
+    pub timestamp: u64,
+    /// An excerpt of the content around the matches, split into fragments so
+    /// clients can highlight the matched words.
+    pub snippet: Vec<Fragment>,
+}
+
+#[derive(Deserialize)]
+struct Row {
+    room: String,
+    id: u64,
+    author: String,
+    timestamp: u64,
+    snippet: String,
+}
+
+pub async fn search(
+    db: &D1Database,
+    query: &GlobalQuery,
+    reader: &Reader<'_>,
+) -> ApiResult<GlobalResults> {
+    let optional = |value: Option<JsValue>| value.unwrap_or(JsValue::NULL);
+    let rooms = match &reader.rooms {
+        Some(rooms) => serde_json::to_string(rooms)
+            .map_err(|error| AppError::Internal(error.to_string()))?
+            .into(),
+        None => JsValue::NULL,
+    };
+
+    let rows = db
+        .prepare(SEARCH_SQL)
+        .bind(&[
+            query.fts_query().into(),
+            optional(query.author.as_deref().map(JsValue::from)),
+            optional(query.room.as_deref().map(JsValue::from)),
+            optional(query.since.map(number)),
+            optional(query.until.map(number)),
+            optional(query.has_attachment.map(|has| number(has as u64))),
+            rooms,
+            number(reader.moderator as u64),
+            optional(reader.user.map(JsValue::from)),
+            number(query.limit as u64 + 1),
+            number(query.offset as u64),
+        ])?
+        .all()
+        .await?
+        .results::<Row>()?;
+
+    let has_more = rows.len() > query.limit;
+    let hits = rows
+        .into_iter()
+        .take(query.limit)
+        .map(|row| GlobalHit {
+            room: row.room,
+            message_id: row.id,
+            author: row.author,
+            timestamp: row.timestamp,
+            snippet: fragments(&row.snippet),
+        })
+        .collect();
+
+    Ok(GlobalResults {
+        hits,
+        next_offset: has_more.then_some(query.offset + query.limit),
+    })
+}
+
+/// Splits a snippet built by SQLite at its match markers.
+fn fragments(snippet: &str) -> Vec<Fragment> {
+    let mut fragments = Vec::new();
+    let mut text = String::new();
+    let mut highlight = false;
+
+    for c in snippet.chars().chain([MATCH_START]) {
+        if c != MATCH_START && c != MATCH_END {
+            text.push(c);
+            continue;
+        }
+        if !text.is_empty() {
+            fragments.push(Fragment {
+                text: std::mem::take(&mut text),
+                highlight,
+            });
+        }
+        highlight = c == MATCH_START;
+    }
+
+    fragments
+}
+
+/// The rooms a backfill was started in.
+#[derive(Serialize, Deserialize)]
+pub struct Backfill {
+    pub rooms: usize,
+}
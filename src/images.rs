@@ -0,0 +1,598 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+
+use crate::attachments;
+use crate::error::{ApiResult, AppError};
+use crate::validation::InvalidParam;
+
+/// Largest width and height of a thumbnail, in pixels. Smaller images get no
+/// thumbnail, clients show the original instead.
+pub const THUMBNAIL_SIZE: u32 = 320;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Memory the decoder may allocate for an image. Larger images are stored
+/// without a thumbnail.
+const MAX_DECODE_ALLOC: u64 = 64 * 1024 * 1024;
+
+/// TIFF tag pointing to the GPS directory of an EXIF block.
+const GPS_IFD_TAG: u16 = 0x8825;
+
+/// Signatures starting the JPEG APP1 segments holding XMP, and the keyword of
+/// the PNG iTXt chunk holding it.
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const EXTENDED_XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+/// Dimensions and thumbnail of an image attachment.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImageMetadata {
+    /// Width in pixels, as displayed once the EXIF orientation is applied.
+    pub width: u32,
+    pub height: u32,
+    /// Set if a thumbnail is stored under
+    /// [`attachments::thumbnail_key`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<Thumbnail>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Thumbnail {
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/// An image upload, once processed by [`process`].
+pub struct ProcessedImage {
+    /// The MIME type of the actual format of the image.
+    pub content_type: &'static str,
+    pub metadata: ImageMetadata,
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+/// The image formats decoded by [`process`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+}
+
+impl Kind {
+    const ALL: [Kind; 4] = [Kind::Png, Kind::Jpeg, Kind::Gif, Kind::WebP];
+
+    /// Detects the format from the magic bytes at the start of the content.
+    fn sniff(content: &[u8]) -> Option<Kind> {
+        if content.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Kind::Png)
+        } else if content.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(Kind::Jpeg)
+        } else if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+            Some(Kind::Gif)
+        } else if content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+            Some(Kind::WebP)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Kind::Png => "image/png",
+            Kind::Jpeg => "image/jpeg",
+            Kind::Gif => "image/gif",
+            Kind::WebP => "image/webp",
+        }
+    }
+
+    fn format(self) -> ImageFormat {
+        match self {
+            Kind::Png => ImageFormat::Png,
+            Kind::Jpeg => ImageFormat::Jpeg,
+            Kind::Gif => ImageFormat::Gif,
+            Kind::WebP => ImageFormat::WebP,
+        }
+    }
+}
+
+/// Runs an upload through the image pipeline: checks the declared type
+/// against the magic bytes, strips the location in place, and records
+/// the dimensions along with a thumbnail.
+///
+/// Returns `None` for content in a format the pipeline does not decode,
+/// unless it was declared as one it does.
+pub fn process(content: &mut [u8], declared_type: &str) -> ApiResult<Option<ProcessedImage>> {
+    let declared_type = attachments::essence(declared_type);
+    let declared = Kind::ALL
+        .into_iter()
+        .find(|kind| kind.content_type() == declared_type);
+
+    let kind = match (Kind::sniff(content), declared) {
+        (Some(kind), None) => kind,
+        (Some(kind), Some(declared)) if kind == declared => kind,
+        (None, None) => return Ok(None),
+        (_, Some(_)) => {
+            return Err(AppError::Validation(vec![InvalidParam {
+                name: "content_type".to_string(),
+                reason: "does not match the content".to_string(),
+            }]))
+        }
+    };
+
+    strip_location(kind, content);
+
+    let invalid_image = |error: ImageError| {
+        AppError::Validation(vec![InvalidParam {
+            name: "content".to_string(),
+            reason: format!("is not a valid image: {error}"),
+        }])
+    };
+
+    let mut reader = ImageReader::with_format(Cursor::new(&*content), kind.format());
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(invalid_image)?;
+    let orientation = decoder.orientation().map_err(invalid_image)?;
+    let (width, height) = decoder.dimensions();
+    let (width, height) = if matches!(
+        orientation,
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH
+    ) {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    let mut processed = ProcessedImage {
+        content_type: kind.content_type(),
+        metadata: ImageMetadata {
+            width,
+            height,
+            thumbnail: None,
+        },
+        thumbnail: None,
+    };
+    if width <= THUMBNAIL_SIZE && height <= THUMBNAIL_SIZE {
+        return Ok(Some(processed));
+    }
+
+    let mut image = match DynamicImage::from_decoder(decoder) {
+        Ok(image) => image,
+        Err(ImageError::Limits(_)) => return Ok(Some(processed)),
+        Err(error) => return Err(invalid_image(error)),
+    };
+    image.apply_orientation(orientation);
+
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let (encoded, content_type) =
+        encode_thumbnail(&thumbnail).map_err(|error| AppError::Internal(error.to_string()))?;
+    processed.metadata.thumbnail = Some(Thumbnail {
+        content_type: content_type.to_string(),
+        width: thumbnail.width(),
+        height: thumbnail.height(),
+        size: encoded.len() as u64,
+    });
+    processed.thumbnail = Some(encoded);
+
+    Ok(Some(processed))
+}
+
+/// Encodes a thumbnail as a JPEG, or as a PNG if it has transparency.
+fn encode_thumbnail(thumbnail: &DynamicImage) -> Result<(Vec<u8>, &'static str), ImageError> {
+    let mut encoded = Vec::new();
+    if thumbnail.color().has_alpha() {
+        thumbnail.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)?;
+        return Ok((encoded, Kind::Png.content_type()));
+    }
+
+    let encoder = JpegEncoder::new_with_quality(&mut encoded, THUMBNAIL_QUALITY);
+    DynamicImage::ImageRgb8(thumbnail.to_rgb8()).write_with_encoder(encoder)?;
+
+    Ok((encoded, Kind::Jpeg.content_type()))
+}
+
+/// Empties the GPS directory of the EXIF blocks of an image, keeping the
+/// rest of the metadata, such as the orientation, intact, and blanks its XMP
+/// packets, which may also hold the location. Blocks are edited in place so
+/// the offsets within the file stay valid.
+fn strip_location(kind: Kind, content: &mut [u8]) {
+    match kind {
+        Kind::Jpeg => {
+            // Segments up to the image data, each a marker and a big-endian
+            // length covering the length itself.
+            let mut position = 2;
+            while position + 4 <= content.len() && content[position] == 0xff {
+                let marker = content[position + 1];
+                if marker == 0xff {
+                    position += 1;
+                    continue;
+                }
+                if marker == 0xda || marker == 0xd9 {
+                    break;
+                }
+                let length =
+                    u16::from_be_bytes([content[position + 2], content[position + 3]]) as usize;
+                if length < 2 {
+                    break;
+                }
+                let end = (position + 2 + length).min(content.len());
+                let segment = &mut content[position + 4..end];
+                if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+                    strip_gps(&mut segment[6..]);
+                } else if marker == 0xe1 {
+                    for signature in [XMP_SIGNATURE, EXTENDED_XMP_SIGNATURE] {
+                        if segment.starts_with(signature) {
+                            blank(&mut segment[signature.len()..]);
+                        }
+                    }
+                }
+                position += 2 + length;
+            }
+        }
+        Kind::Png => {
+            // Chunks after the signature: a big-endian length, the type, the
+            // data and a CRC of the type and data.
+            let mut position = 8;
+            while position + 12 <= content.len() {
+                let length = u32::from_be_bytes(
+                    content[position..position + 4]
+                        .try_into()
+                        .unwrap_or_default(),
+                ) as usize;
+                // Lengths near `u32::MAX` would wrap on 32-bit targets.
+                let Some(end) = (position + 8)
+                    .checked_add(length)
+                    .filter(|end| end.checked_add(4).is_some_and(|crc| crc <= content.len()))
+                else {
+                    break;
+                };
+                match &content[position + 4..position + 8] {
+                    b"eXIf" => {
+                        strip_gps(&mut content[position + 8..end]);
+                        let crc = crc32fast::hash(&content[position + 4..end]);
+                        content[end..end + 4].copy_from_slice(&crc.to_be_bytes());
+                    }
+                    b"iTXt" if content[position + 8..end].starts_with(XMP_KEYWORD) => {
+                        blank_itxt(&mut content[position + 8 + XMP_KEYWORD.len()..end]);
+                        let crc = crc32fast::hash(&content[position + 4..end]);
+                        content[end..end + 4].copy_from_slice(&crc.to_be_bytes());
+                    }
+                    b"IEND" => break,
+                    _ => {}
+                }
+                position = end + 4;
+            }
+        }
+        Kind::WebP => {
+            // RIFF chunks after the header: the type, a little-endian length
+            // and the data, padded to an even length.
+            let mut position = 12;
+            while position + 8 <= content.len() {
+                let length = u32::from_le_bytes(
+                    content[position + 4..position + 8]
+                        .try_into()
+                        .unwrap_or_default(),
+                ) as usize;
+                let Some(next) = (position + 8)
+                    .checked_add(length)
+                    .and_then(|end| end.checked_add(length % 2))
+                else {
+                    break;
+                };
+                let end = (next - length % 2).min(content.len());
+                if &content[position..position + 4] == b"EXIF" {
+                    let chunk = &mut content[position + 8..end];
+                    let tiff = if chunk.starts_with(b"Exif\0\0") {
+                        &mut chunk[6..]
+                    } else {
+                        chunk
+                    };
+                    strip_gps(tiff);
+                } else if &content[position..position + 4] == b"XMP " {
+                    blank(&mut content[position + 8..end]);
+                }
+                position = next;
+            }
+        }
+        // GIF has no EXIF.
+        Kind::Gif => {}
+    }
+}
+
+/// Overwrites an XMP packet with spaces, which XMP readers skip as padding.
+fn blank(packet: &mut [u8]) {
+    packet.fill(b' ');
+}
+
+/// Blanks the text of a PNG iTXt chunk, given its data after the keyword,
+/// marking it uncompressed with no language or translated keyword. Chunks
+/// too short to hold those fields are blanked whole.
+fn blank_itxt(data: &mut [u8]) {
+    const EMPTY_FIELDS: [u8; 4] = [0, 0, 0, 0];
+
+    blank(data);
+    if let Some(fields) = data.get_mut(..EMPTY_FIELDS.len()) {
+        fields.copy_from_slice(&EMPTY_FIELDS);
+    }
+}
+
+/// Empties the GPS directory of a TIFF structure, the layout of EXIF blocks:
+/// its entries and their values are zeroed, and its entry count set to 0.
+fn strip_gps(tiff: &mut [u8]) {
+    let big_endian = match tiff.get(..4) {
+        Some(b"II*\0") => false,
+        Some(b"MM\0*") => true,
+        _ => return,
+    };
+    let mut tiff = Tiff {
+        data: tiff,
+        big_endian,
+    };
+
+    // Offsets past the end are skipped, which keeps the arithmetic on
+    // entries below from overflowing.
+    let Some(ifd0) = tiff
+        .u32(4)
+        .filter(|ifd0| (*ifd0 as usize) < tiff.data.len())
+    else {
+        return;
+    };
+    let Some(count) = tiff.u16(ifd0 as usize) else {
+        return;
+    };
+    for index in 0..count as usize {
+        let entry = ifd0 as usize + 2 + index * 12;
+        if tiff.u16(entry) == Some(GPS_IFD_TAG) {
+            if let Some(gps) = tiff.u32(entry + 8) {
+                tiff.clear_ifd(gps as usize);
+            }
+        }
+    }
+}
+
+struct Tiff<'a> {
+    data: &'a mut [u8],
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(2)?)?
+            .try_into()
+            .ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn zero(&mut self, offset: usize, length: usize) {
+        let end = offset.saturating_add(length).min(self.data.len());
+        if let Some(bytes) = self.data.get_mut(offset..end) {
+            bytes.fill(0);
+        }
+    }
+
+    /// Zeroes the entries of a directory and their values stored out of
+    /// line, leaving an empty directory behind.
+    fn clear_ifd(&mut self, offset: usize) {
+        if offset >= self.data.len() {
+            return;
+        }
+        let Some(count) = self.u16(offset) else {
+            return;
+        };
+
+        for index in 0..count as usize {
+            let entry = offset + 2 + index * 12;
+            let (Some(kind), Some(values)) = (self.u16(entry + 2), self.u32(entry + 4)) else {
+                break;
+            };
+            let size = type_size(kind).saturating_mul(values as usize);
+            if size > 4 {
+                if let Some(value_offset) = self.u32(entry + 8) {
+                    self.zero(value_offset as usize, size);
+                }
+            }
+            self.zero(entry, 12);
+        }
+
+        // With no entries, the zeroed first entry reads as the offset of
+        // the next directory: none.
+        self.zero(offset, 2);
+    }
+}
+
+/// Size in bytes of a value of a TIFF field type, 0 for unknown types.
+fn type_size(kind: u16) -> usize {
+    match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    /// A big-endian TIFF block whose first directory points to a GPS
+    /// directory holding a latitude.
+    fn tiff_with_location() -> Vec<u8> {
+        let mut tiff = b"MM\0*".to_vec();
+        tiff.extend(8u32.to_be_bytes());
+        // IFD0: one entry, the GPS directory at offset 26.
+        tiff.extend(1u16.to_be_bytes());
+        tiff.extend(GPS_IFD_TAG.to_be_bytes());
+        tiff.extend(4u16.to_be_bytes());
+        tiff.extend(1u32.to_be_bytes());
+        tiff.extend(26u32.to_be_bytes());
+        tiff.extend(0u32.to_be_bytes());
+        // GPS IFD: the latitude, three rationals at offset 44.
+        tiff.extend(1u16.to_be_bytes());
+        tiff.extend(2u16.to_be_bytes());
+        tiff.extend(5u16.to_be_bytes());
+        tiff.extend(3u32.to_be_bytes());
+        tiff.extend(44u32.to_be_bytes());
+        tiff.extend(0u32.to_be_bytes());
+        tiff.extend([0x42; 24]);
+        tiff
+    }
+
+    fn png_chunk(kind: &[u8], length: u32, data: &[u8]) -> Vec<u8> {
+        let mut chunk = length.to_be_bytes().to_vec();
+        chunk.extend(kind);
+        chunk.extend(data);
+        chunk.extend(0u32.to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn strips_the_gps_directory_of_png() {
+        let tiff = tiff_with_location();
+        let mut content = PNG_SIGNATURE.to_vec();
+        content.extend(png_chunk(b"eXIf", tiff.len() as u32, &tiff));
+        content.extend(png_chunk(b"IEND", 0, &[]));
+
+        strip_location(Kind::Png, &mut content);
+
+        let stripped = &content[16..16 + tiff.len()];
+        assert_eq!(&stripped[..26], &tiff[..26]);
+        assert!(stripped[26..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn ignores_png_chunks_with_huge_lengths() {
+        let mut content = PNG_SIGNATURE.to_vec();
+        content.extend(png_chunk(b"eXIf", u32::MAX - 4, &tiff_with_location()));
+        let original = content.clone();
+
+        strip_location(Kind::Png, &mut content);
+
+        assert_eq!(content, original);
+    }
+
+    #[test]
+    fn ignores_truncated_png_chunks() {
+        let mut content = PNG_SIGNATURE.to_vec();
+        content.extend(png_chunk(b"eXIf", 4096, &tiff_with_location()));
+        let original = content.clone();
+
+        strip_location(Kind::Png, &mut content);
+
+        assert_eq!(content, original);
+    }
+
+    #[test]
+    fn stops_at_webp_chunks_with_huge_lengths() {
+        let mut content = b"RIFF\0\0\0\0WEBP".to_vec();
+        content.extend(b"VP8X");
+        content.extend(u32::MAX.to_le_bytes());
+        content.extend(b"EXIF");
+        content.extend(8u32.to_le_bytes());
+        content.extend(tiff_with_location());
+        let original = content.clone();
+
+        strip_location(Kind::WebP, &mut content);
+
+        assert_eq!(content, original);
+    }
+
+    const XMP_PACKET: &[u8] =
+        b"<x:xmpmeta><rdf:Description exif:GPSLatitude=\"48,51.4N\"/></x:xmpmeta>";
+
+    #[test]
+    fn blanks_jpeg_xmp() {
+        let mut segment = XMP_SIGNATURE.to_vec();
+        segment.extend(XMP_PACKET);
+        let mut content = vec![0xff, 0xd8, 0xff, 0xe1];
+        content.extend((segment.len() as u16 + 2).to_be_bytes());
+        content.extend(&segment);
+        content.extend([0xff, 0xda, 0, 2]);
+
+        strip_location(Kind::Jpeg, &mut content);
+
+        let packet = &content[6 + XMP_SIGNATURE.len()..6 + segment.len()];
+        assert_eq!(&content[6..6 + XMP_SIGNATURE.len()], XMP_SIGNATURE);
+        assert!(packet.iter().all(|byte| *byte == b' '));
+        assert_eq!(&content[6 + segment.len()..], [0xff, 0xda, 0, 2]);
+    }
+
+    #[test]
+    fn blanks_png_xmp() {
+        let mut data = XMP_KEYWORD.to_vec();
+        data.extend([1, 0]);
+        data.extend(b"en\0\0");
+        data.extend(XMP_PACKET);
+        let mut content = PNG_SIGNATURE.to_vec();
+        content.extend(png_chunk(b"iTXt", data.len() as u32, &data));
+        content.extend(png_chunk(b"IEND", 0, &[]));
+
+        strip_location(Kind::Png, &mut content);
+
+        let chunk = &content[16..16 + data.len()];
+        let text = &chunk[XMP_KEYWORD.len()..];
+        assert_eq!(&chunk[..XMP_KEYWORD.len()], XMP_KEYWORD);
+        assert_eq!(&text[..4], [0, 0, 0, 0]);
+        assert!(text[4..].iter().all(|byte| *byte == b' '));
+        let crc = crc32fast::hash(&content[12..16 + data.len()]);
+        assert_eq!(content[16 + data.len()..20 + data.len()], crc.to_be_bytes());
+    }
+
+    #[test]
+    fn blanks_webp_xmp() {
+        let mut content = b"RIFF\0\0\0\0WEBP".to_vec();
+        content.extend(b"XMP ");
+        content.extend((XMP_PACKET.len() as u32).to_le_bytes());
+        content.extend(XMP_PACKET);
+
+        strip_location(Kind::WebP, &mut content);
+
+        assert_eq!(&content[12..16], b"XMP ");
+        assert!(content[20..].iter().all(|byte| *byte == b' '));
+    }
+
+    #[test]
+    fn ignores_directories_past_the_end() {
+        let mut tiff = tiff_with_location();
+        tiff[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        let original = tiff.clone();
+
+        strip_gps(&mut tiff);
+
+        assert_eq!(tiff, original);
+    }
+}
@@ -1,36 +1,175 @@
 use std::sync::Arc;
 
-use serde::{Deserialize, Serialize};
 use tower_service::Service;
 use wasm_bindgen_futures::wasm_bindgen::JsValue;
 use worker::*;
 
+mod accounts;
+mod acl;
+mod api_keys;
+mod attachments;
+mod auth;
+mod chatroom;
+mod directory;
+mod error;
+mod global_search;
+mod images;
+mod moderation;
+mod oidc;
+mod presence;
+mod rate_limit;
+mod retention;
+mod search;
+mod storage;
+mod validation;
+
+use acl::{AccessSettings, Acl, Invite, NewAcl, RoleChange};
+use api_keys::{Access, ApiKey, ApiKeyUpdate, MintedApiKey, NewApiKey};
+use attachments::{Attachment, AttachmentLimits, NewAttachment};
+use auth::{Credentials, OptionalUser, Session, User};
+use chatroom::{Message, MessageEdit, MessageList, NewMessage, NewReaction, QueuedMessage};
+use directory::{CreateRoom, RoomInfo};
+use error::{ApiResult, AppError, Json, Path, Problem};
+use global_search::{Backfill, GlobalQuery, GlobalResults, Reader};
+use moderation::ContentRules;
+use presence::Presence;
+use rate_limit::RateLimits;
+use retention::{NewLegalHold, Retention, RetentionPolicy};
+use search::SearchResults;
+
+/// Room backing the un-scoped `/api/messages` routes.
+const DEFAULT_ROOM: &str = "CHATROOM";
+
 #[derive(Clone)]
 pub struct AppState {
     env: Arc<Env>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct MessageList {
-    messages: Vec<Message>,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-struct Message {
-    content: String,
-}
-
 fn router(env: Env) -> axum::Router {
     let state = AppState { env: Arc::new(env) };
     axum::Router::new()
         .route("/", axum::routing::get(root))
+        .nest(
+            "/auth",
+            axum::Router::new()
+                .route("/register", axum::routing::post(register))
+                .route("/login", axum::routing::get(oidc_login).post(login))
+                .route("/callback", axum::routing::get(oidc_callback))
+                .route("/logout", axum::routing::post(logout)),
+        )
         .nest(
             "/api",
-            axum::Router::new().route(
-                "/messages",
-                axum::routing::get(get_messages).post(post_messages),
-            ),
+            axum::Router::new()
+                .route(
+                    "/messages",
+                    axum::routing::get(get_messages).post(post_messages),
+                )
+                .route("/keys", axum::routing::get(get_keys).post(post_keys))
+                .route(
+                    "/keys/:id",
+                    axum::routing::patch(patch_key).delete(delete_key),
+                )
+                .route("/attachments/:id", axum::routing::get(get_attachment))
+                .route(
+                    "/attachments/:id/thumbnail",
+                    axum::routing::get(get_attachment_thumbnail),
+                )
+                .route("/search", axum::routing::get(get_search))
+                .route(
+                    "/search/backfill",
+                    axum::routing::post(post_search_backfill),
+                )
+                .route("/rooms", axum::routing::get(get_rooms).post(post_rooms))
+                .route(
+                    "/rooms/:room/messages",
+                    axum::routing::get(get_room_messages)
+                        .post(post_room_messages)
+                        .delete(delete_room_messages),
+                )
+                .route(
+                    "/rooms/:room/messages/:id",
+                    axum::routing::get(get_room_message)
+                        .patch(patch_room_message)
+                        .delete(delete_room_message),
+                )
+                .route(
+                    "/rooms/:room/access",
+                    axum::routing::patch(patch_room_access),
+                )
+                .route(
+                    "/rooms/:room/limits",
+                    axum::routing::get(get_room_limits).put(put_room_limits),
+                )
+                .route(
+                    "/rooms/:room/retention",
+                    axum::routing::get(get_room_retention).put(put_room_retention),
+                )
+                .route(
+                    "/rooms/:room/retention/hold",
+                    axum::routing::put(put_room_legal_hold).delete(delete_room_legal_hold),
+                )
+                .route(
+                    "/rooms/:room/rules",
+                    axum::routing::get(get_room_rules).put(put_room_rules),
+                )
+                .route(
+                    "/rooms/:room/moderation",
+                    axum::routing::get(get_room_moderation),
+                )
+                .route(
+                    "/rooms/:room/moderation/:id",
+                    axum::routing::delete(delete_room_moderation),
+                )
+                .route("/rooms/:room/members", axum::routing::get(get_room_members))
+                .route(
+                    "/rooms/:room/members/:user",
+                    axum::routing::patch(patch_room_member).delete(delete_room_member),
+                )
+                .route(
+                    "/rooms/:room/invites",
+                    axum::routing::post(post_room_invites),
+                )
+                .route("/rooms/:room/join", axum::routing::post(post_room_join))
+                .route("/rooms/:room/leave", axum::routing::post(post_room_leave))
+                .route(
+                    "/rooms/:room/bans/:user",
+                    axum::routing::put(put_room_ban).delete(delete_room_ban),
+                )
+                .route(
+                    "/rooms/:room/messages/:id/reactions/:emoji",
+                    axum::routing::put(put_room_reaction).delete(delete_room_reaction),
+                )
+                .route(
+                    "/rooms/:room/messages/:id/thread",
+                    axum::routing::get(get_room_thread),
+                )
+                .route(
+                    "/rooms/:room/attachments",
+                    axum::routing::post(post_room_attachments).layer(
+                        axum::extract::DefaultBodyLimit::max(attachments::MAX_ATTACHMENT_SIZE),
+                    ),
+                )
+                .route(
+                    "/rooms/:room/attachments/limits",
+                    axum::routing::get(get_room_attachment_limits).put(put_room_attachment_limits),
+                )
+                .route(
+                    "/rooms/:room/presence",
+                    axum::routing::get(get_room_presence).post(post_room_presence),
+                )
+                .route("/rooms/:room/typing", axum::routing::post(post_room_typing))
+                .route("/rooms/:room/search", axum::routing::get(get_room_search))
+                .route("/rooms/:room/ws", axum::routing::get(get_room_websocket))
+                .route("/rooms/:room/events", axum::routing::get(get_room_events)),
         )
+        .fallback(not_found)
+        .layer(axum::extract::DefaultBodyLimit::max(
+            validation::MAX_BODY_SIZE,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_ip,
+        ))
         .with_state(state)
 }
 
@@ -46,130 +185,1721 @@ async fn fetch(
 
 #[worker::send]
 pub async fn get_messages(
+    state: axum::extract::State<AppState>,
+    user: OptionalUser,
+    query: axum::extract::RawQuery,
+) -> ApiResult<axum::Json<MessageList>> {
+    get_room_messages(state, user, Path(DEFAULT_ROOM.to_string()), query).await
+}
+
+#[worker::send]
+pub async fn post_messages(
+    state: axum::extract::State<AppState>,
+    user: User,
+    query: axum::extract::RawQuery,
+    payload: Json<NewMessage>,
+) -> ApiResult<axum::response::Response> {
+    post_room_messages(state, user, Path(DEFAULT_ROOM.to_string()), query, payload).await
+}
+
+/// Lists a page of the room's messages. Takes `before`, `after` and `limit`
+/// query parameters, see the `next_cursor` and `prev_cursor` of the response.
+#[worker::send]
+pub async fn get_room_messages(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> ApiResult<axum::Json<MessageList>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &with_query("/messages", query),
+            worker::Method::Get,
+            None,
+            user.as_ref(),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+#[worker::send]
+pub async fn get_room_message(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path((room, id)): Path<(String, u64)>,
+) -> ApiResult<axum::Json<Message>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/messages/{id}"),
+            worker::Method::Get,
+            None,
+            user.as_ref(),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Reacts to a message with an emoji or a custom `:shortcode:`, which must be
+/// percent-encoded in the path. Reacting twice with the same emoji is a no-op.
+#[worker::send]
+pub async fn put_room_reaction(
+    state: axum::extract::State<AppState>,
+    user: User,
+    Path((room, id, emoji)): Path<(String, u64, String)>,
+) -> ApiResult<axum::Json<Message>> {
+    react(state, user, room, id, emoji, worker::Method::Post).await
+}
+
+#[worker::send]
+pub async fn delete_room_reaction(
+    state: axum::extract::State<AppState>,
+    user: User,
+    Path((room, id, emoji)): Path<(String, u64, String)>,
+) -> ApiResult<axum::Json<Message>> {
+    react(state, user, room, id, emoji, worker::Method::Delete).await
+}
+
+async fn react(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    room: String,
+    id: u64,
+    emoji: String,
+    method: worker::Method,
+) -> ApiResult<axum::Json<Message>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&NewReaction { emoji }).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/messages/{id}/reactions"),
+            method,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Lists a page of the replies in the thread started by a message. Takes the
+/// same `before`, `after` and `limit` query parameters as the room's messages.
+#[worker::send]
+pub async fn get_room_thread(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path((room, id)): Path<(String, u64)>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> ApiResult<axum::Json<MessageList>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &with_query(&format!("/messages/{id}/thread"), query),
+            worker::Method::Get,
+            None,
+            user.as_ref(),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Edits a message, see `Chatroom::edit_message`.
+#[worker::send]
+pub async fn patch_room_message(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path((room, id)): Path<(String, u64)>,
+    Json(payload): Json<MessageEdit>,
+) -> ApiResult<axum::Json<Message>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/messages/{id}"),
+            worker::Method::Patch,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Replaces a message by a tombstone, see `Chatroom::delete_message`.
+#[worker::send]
+pub async fn delete_room_message(
     axum::extract::State(AppState { env }): axum::extract::State<AppState>,
-) -> impl axum::response::IntoResponse {
+    user: User,
+    Path((room, id)): Path<(String, u64)>,
+) -> ApiResult<axum::Json<Message>> {
+    user.authorize(&room, Access::Write)?;
+
     let response = fetch_chatroom(
         env,
-        worker::Request::new("http://fake-host/messages", worker::Method::Get).unwrap(),
+        &room,
+        chatroom_request(
+            &format!("/messages/{id}"),
+            worker::Method::Delete,
+            None,
+            Some(&user),
+        )?,
     )
-    .await
-    .unwrap();
+    .await?;
 
-    let parsed = serde_json::from_str::<MessageList>(&response).unwrap();
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Deletes the messages matching the `by`, `since` and `until` query
+/// parameters, see `Chatroom::delete_messages`.
+#[worker::send]
+pub async fn delete_room_messages(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &with_query("/messages", query),
+            worker::Method::Delete,
+            None,
+            Some(&user),
+        )?,
+    )
+    .await?;
 
-    axum::Json(parsed)
+    Ok(axum::Json(read_json(response).await?))
 }
 
+/// Posts a message, responding with the created message. The room's full
+/// history is returned instead when `history=true` is passed, as this route
+/// used to do.
 #[worker::send]
 #[axum::debug_handler]
-pub async fn post_messages(
+pub async fn post_room_messages(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+    Json(payload): Json<NewMessage>,
+) -> ApiResult<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &with_query("/messages", query),
+            worker::Method::Post,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    if response.status_code() != 201 {
+        let history = read_json::<MessageList>(response).await?;
+        return Ok(axum::Json(history).into_response());
+    }
+
+    let message = read_json::<Message>(response).await?;
+    Ok((
+        axum::http::StatusCode::CREATED,
+        [(
+            axum::http::header::LOCATION,
+            format!("/api/rooms/{room}/messages/{}", message.id),
+        )],
+        axum::Json(message),
+    )
+        .into_response())
+}
+
+/// Upgrades to a WebSocket accepted by the room's durable object, which pushes
+/// every new message of the room to the socket. Signed in users may also post
+/// messages through the socket.
+#[worker::send]
+pub async fn get_room_websocket(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    let upgrade = headers
+        .get(axum::http::header::UPGRADE)
+        .and_then(|value| value.to_str().ok());
+    if upgrade != Some("websocket") {
+        return Err(AppError::UpgradeRequired);
+    }
+
+    let mut req = chatroom_request("/websocket", worker::Method::Get, None, user.as_ref())?;
+    req.headers_mut()?.set("Upgrade", "websocket")?;
+
+    // Sockets of API keys limited to reading are not allowed to post.
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+        if user.authorize(&room, Access::Write).is_err() {
+            req.headers_mut()?.set(auth::READ_ONLY_HEADER, "true")?;
+        }
+    }
+
+    let response = fetch_chatroom(env, &room, req).await?;
+    if response.status_code() != 101 {
+        return Err(read_error(response).await);
+    }
+
+    let websocket = response
+        .websocket()
+        .ok_or_else(|| AppError::bad_gateway("Room did not accept the WebSocket"))?;
+
+    // The runtime picks the socket up from the response extensions.
+    let mut response = axum::http::StatusCode::SWITCHING_PROTOCOLS.into_response();
+    response.extensions_mut().insert(websocket);
+
+    Ok(response)
+}
+
+/// Uploads an attachment to a room, to be posted with a message by listing its
+/// id in the message's `attachments`. The body is the raw content of the file,
+/// described by the `Content-Type` header and the `name` query parameter.
+#[worker::send]
+pub async fn post_room_attachments(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+    headers: axum::http::HeaderMap,
+    body: std::result::Result<axum::body::Bytes, axum::extract::rejection::BytesRejection>,
+) -> ApiResult<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    user.authorize(&room, Access::Write)?;
+    let body = body?;
+
+    let name = query
+        .as_deref()
+        .and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(name, _)| name == "name")
+                .map(|(_, value)| value.into_owned())
+        })
+        .unwrap_or_default();
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream");
+
+    let mut content = body.to_vec();
+    let image = images::process(&mut content, content_type)?;
+    let content_type = image
+        .as_ref()
+        .map_or(content_type, |image| image.content_type);
+
+    let mut new_attachment = NewAttachment::new(&name, content_type, &content);
+    new_attachment.image = image.as_ref().map(|image| image.metadata.clone());
+    let json = serde_json::to_string(&new_attachment).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env.clone(),
+        &room,
+        chatroom_request(
+            "/attachments",
+            worker::Method::Post,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+    let attachment = read_json::<Attachment>(response).await?;
+
+    // Uploads that fail past this point are never posted, so the room prunes
+    // their record.
+    let bucket = env.bucket(attachments::BUCKET)?;
+    if let Some((thumbnail, metadata)) =
+        image.and_then(|image| image.thumbnail.zip(image.metadata.thumbnail))
+    {
+        bucket
+            .put(attachments::thumbnail_key(&attachment.id), thumbnail)
+            .http_metadata(worker::HttpMetadata {
+                content_type: Some(metadata.content_type),
+                ..Default::default()
+            })
+            .execute()
+            .await?;
+    }
+    bucket
+        .put(attachments::object_key(&attachment.id), content)
+        .http_metadata(worker::HttpMetadata {
+            content_type: Some(attachment.content_type.clone()),
+            ..Default::default()
+        })
+        .custom_metadata([(attachments::ROOM_METADATA.to_string(), room)])
+        .execute()
+        .await?;
+
+    Ok((
+        axum::http::StatusCode::CREATED,
+        [(
+            axum::http::header::LOCATION,
+            format!("/api/attachments/{}", attachment.id),
+        )],
+        axum::Json(attachment),
+    )
+        .into_response())
+}
+
+/// Downloads an attachment. Readers of the room it was posted to may do so,
+/// and only its uploader until it is posted. A `Range` header fetches part of
+/// the content.
+#[worker::send]
+pub async fn get_attachment(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(id): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult<axum::response::Response> {
+    use axum::http::header;
+
+    let (bucket, attachment) = find_attachment(env, user.as_ref(), &id).await?;
+    let not_found = || AppError::NotFound(format!("No attachment with id {id}"));
+
+    let range = match headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(range) => attachments::parse_range(range, attachment.size)?,
+        None => None,
+    };
+
+    let mut get = bucket.get(attachments::object_key(&id));
+    if let Some(range) = range {
+        // R2 takes 32-bit ranges, which attachments never exceed.
+        let unsatisfiable = || AppError::RangeNotSatisfiable {
+            size: attachment.size,
+        };
+        get = get.range(worker::Range::OffsetWithLength {
+            offset: u32::try_from(range.start).map_err(|_| unsatisfiable())?,
+            length: u32::try_from(range.length()).map_err(|_| unsatisfiable())?,
+        });
+    }
+    let object = get.execute().await?.ok_or_else(not_found)?;
+    let content = object.body().ok_or_else(not_found)?.bytes().await?;
+
+    let mut response = axum::http::Response::builder()
+        .header(header::CONTENT_TYPE, &attachment.content_type)
+        .header(header::CONTENT_LENGTH, content.len())
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, format!("\"{}\"", attachment.sha256))
+        .header(
+            header::CONTENT_DISPOSITION,
+            attachments::content_disposition(&attachment.name),
+        )
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(
+            header::CACHE_CONTROL,
+            "private, max-age=31536000, immutable",
+        );
+    if let Some(range) = range {
+        response = response
+            .status(axum::http::StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end, attachment.size),
+            );
+    }
+
+    response
+        .body(axum::body::Body::from(content))
+        .map_err(|error| AppError::Internal(error.to_string()))
+}
+
+/// Downloads the thumbnail of an image attachment, found in its
+/// `image.thumbnail`.
+#[worker::send]
+pub async fn get_attachment_thumbnail(
     axum::extract::State(AppState { env }): axum::extract::State<AppState>,
-    axum::extract::Json(payload): axum::extract::Json<Message>,
-) -> impl axum::response::IntoResponse {
-    let json = serde_json::to_string(&payload).unwrap();
-
-    let response = fetch_chatroom(
-        env,
-        worker::Request::new_with_init(
-            "http://fake-host/messages",
-            &RequestInit {
-                body: Some(JsValue::from_str(json.as_str())),
-                headers: worker::Headers::new(),
-                cf: CfProperties {
-                    apps: None,
-                    cache_everything: None,
-                    cache_key: None,
-                    cache_ttl: None,
-                    cache_ttl_by_status: None,
-                    minify: None,
-                    mirage: None,
-                    polish: None,
-                    resolve_override: None,
-                    scrape_shield: None,
-                },
-                method: worker::Method::Post,
-                redirect: RequestRedirect::Manual,
-            },
+    OptionalUser(user): OptionalUser,
+    Path(id): Path<String>,
+) -> ApiResult<axum::response::Response> {
+    use axum::http::header;
+
+    let (bucket, attachment) = find_attachment(env, user.as_ref(), &id).await?;
+    let not_found = || AppError::NotFound(format!("Attachment {id} has no thumbnail"));
+
+    let thumbnail = attachment
+        .image
+        .and_then(|image| image.thumbnail)
+        .ok_or_else(not_found)?;
+    let object = bucket
+        .get(attachments::thumbnail_key(&id))
+        .execute()
+        .await?
+        .ok_or_else(not_found)?;
+    let content = object.body().ok_or_else(not_found)?.bytes().await?;
+
+    axum::http::Response::builder()
+        .header(header::CONTENT_TYPE, thumbnail.content_type)
+        .header(header::CONTENT_LENGTH, content.len())
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(
+            header::CACHE_CONTROL,
+            "private, max-age=31536000, immutable",
         )
-        .unwrap(),
+        .body(axum::body::Body::from(content))
+        .map_err(|error| AppError::Internal(error.to_string()))
+}
+
+/// The size and type limits of the attachments of a room.
+#[worker::send]
+pub async fn get_room_attachment_limits(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<AttachmentLimits>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            "/attachments/limits",
+            worker::Method::Get,
+            None,
+            user.as_ref(),
+        )?,
     )
-    .await
-    .unwrap();
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Replaces the attachment limits of a room. Moderators and owners may do so.
+#[worker::send]
+pub async fn put_room_attachment_limits(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<AttachmentLimits>,
+) -> ApiResult<axum::Json<AttachmentLimits>> {
+    user.authorize(&room, Access::Write)?;
 
-    let parsed = serde_json::from_str::<MessageList>(&response).unwrap();
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            "/attachments/limits",
+            worker::Method::Put,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
 
-    axum::Json(parsed)
+    Ok(axum::Json(read_json(response).await?))
 }
 
+/// Searches the history of a room for messages holding every word of `q`,
+/// each matched as a prefix, newest first. `author`, `since`, `until` and
+/// `has_attachment` narrow the search, and `before` pages through the hits.
 #[worker::send]
-pub async fn root(
+pub async fn get_room_search(
     axum::extract::State(AppState { env }): axum::extract::State<AppState>,
-) -> impl axum::response::IntoResponse {
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> ApiResult<axum::Json<SearchResults>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
     let response = fetch_chatroom(
         env,
-        worker::Request::new("http://fake-host/", worker::Method::Get).unwrap(),
+        &room,
+        chatroom_request(
+            &with_query("/search", query),
+            worker::Method::Get,
+            None,
+            user.as_ref(),
+        )?,
     )
-    .await
-    .unwrap();
+    .await?;
 
-    axum::Json(response)
+    Ok(axum::Json(read_json(response).await?))
 }
 
-#[allow(dead_code)]
-#[durable_object]
-pub struct Chatroom {
-    messages: Vec<Message>,
-    env: Env,
-    state: State,
+/// Searches the messages of every room the caller may read for those holding
+/// every word of `q`, each matched as a prefix, newest first. `author`,
+/// `room`, `since`, `until` and `has_attachment` narrow the search, and
+/// `offset` pages through the hits. Global moderators search every room.
+#[worker::send]
+pub async fn get_search(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> ApiResult<axum::Json<GlobalResults>> {
+    let query = GlobalQuery::from_query(query.as_deref())?;
+
+    let rooms = user
+        .as_ref()
+        .and_then(|user| user.scopes.as_ref())
+        .filter(|scopes| !scopes.iter().any(|scope| scope.room == "*"))
+        .map(|scopes| scopes.iter().map(|scope| scope.room.clone()).collect());
+    let reader = Reader {
+        user: user.as_ref().map(|user| user.name.as_str()),
+        moderator: user
+            .as_ref()
+            .is_some_and(|user| auth::is_moderator(&env, &user.name)),
+        rooms,
+    };
+
+    let db = env.d1(global_search::DATABASE)?;
+    Ok(axum::Json(
+        global_search::search(&db, &query, &reader).await?,
+    ))
+}
+
+/// Mirrors the existing messages of the default room and of every room in the
+/// directory to the cross-room search index. Rooms used by name without being
+/// created are not listed anywhere, and are skipped: only the messages posted
+/// to them from now on are mirrored. Each room works through its history in
+/// the background. Global moderators may do so.
+#[worker::send]
+pub async fn post_search_backfill(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+) -> ApiResult<(axum::http::StatusCode, axum::Json<Backfill>)> {
+    user.require_session()?;
+    if !auth::is_moderator(&env, &user.name) {
+        return Err(AppError::Forbidden(
+            "Only global moderators may backfill the search index".to_string(),
+        ));
+    }
+
+    let response = fetch_directory(
+        env.clone(),
+        chatroom_request("/rooms", worker::Method::Get, None, None)?,
+    )
+    .await?;
+    let listed = read_json::<Vec<RoomInfo>>(response).await?;
+    let rooms = std::iter::once(DEFAULT_ROOM)
+        .chain(listed.iter().map(RoomInfo::address))
+        .collect::<Vec<_>>();
+
+    for room in &rooms {
+        let response = fetch_chatroom(
+            env.clone(),
+            room,
+            chatroom_request("/search/backfill", worker::Method::Post, None, Some(&user))?,
+        )
+        .await?;
+        if response.status_code() >= 400 {
+            return Err(read_error(response).await);
+        }
+    }
+
+    Ok((
+        axum::http::StatusCode::ACCEPTED,
+        axum::Json(Backfill { rooms: rooms.len() }),
+    ))
+}
+
+/// Lists the users online in a room: those with an open WebSocket or who sent
+/// a heartbeat in the last minute.
+#[worker::send]
+pub async fn get_room_presence(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Vec<Presence>>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/presence", worker::Method::Get, None, user.as_ref())?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Sends a heartbeat keeping the caller online in a room, for clients
+/// following the room through Server-Sent Events. WebSocket clients send
+/// `{"type": "heartbeat"}` frames instead.
+#[worker::send]
+pub async fn post_room_presence(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Presence>> {
+    user.authorize(&room, Access::Read)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/presence", worker::Method::Post, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Tells a room the caller is typing. WebSocket clients send
+/// `{"type": "typing"}` frames instead.
+#[worker::send]
+pub async fn post_room_typing(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Presence>> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/typing", worker::Method::Post, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Streams the room's events as Server-Sent Events, for clients that cannot
+/// open a WebSocket. Joins, leaves and typing are sent as `presence` events.
+/// New messages, edits and deletions carry an id, and are replayed after the
+/// one named by `Last-Event-ID` when reconnecting.
+#[worker::send]
+pub async fn get_room_events(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult<axum::response::Response> {
+    use futures_util::{Stream, TryStreamExt};
+
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let mut req = chatroom_request("/events", worker::Method::Get, None, user.as_ref())?;
+    if let Some(last_event_id) = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+    {
+        req.headers_mut()?.set("Last-Event-ID", last_event_id)?;
+    }
+
+    let mut response = fetch_chatroom(env, &room, req).await?;
+    if response.status_code() != 200 {
+        return Err(read_error(response).await);
+    }
+
+    // Workers run on a single thread, so the JS-backed stream is never
+    // actually sent across threads.
+    let mut events = worker::send::SendWrapper::new(Box::pin(
+        response
+            .stream()
+            .map_err(AppError::bad_gateway)?
+            .map_err(|error| std::io::Error::other(error.to_string())),
+    ));
+    let events = futures_util::stream::poll_fn(move |cx| events.as_mut().poll_next(cx));
+
+    axum::http::Response::builder()
+        .header(axum::http::header::CONTENT_TYPE, "text/event-stream")
+        .header(axum::http::header::CACHE_CONTROL, "no-cache")
+        .body(axum::body::Body::from_stream(events))
+        .map_err(|error| AppError::Internal(error.to_string()))
 }
 
-#[durable_object]
-impl DurableObject for Chatroom {
-    fn new(state: State, env: Env) -> Self {
-        Self {
-            messages: vec![],
-            env,
-            state,
+/// Lists the rooms created through the API. Private rooms are only listed to
+/// those who may read them.
+#[worker::send]
+pub async fn get_rooms(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+) -> ApiResult<axum::Json<Vec<RoomInfo>>> {
+    let response = fetch_directory(
+        env.clone(),
+        chatroom_request("/rooms", worker::Method::Get, None, user.as_ref())?,
+    )
+    .await?;
+    let rooms = read_json::<Vec<RoomInfo>>(response).await?;
+
+    let mut visible = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        // The directory only lists private rooms to their readers, but API
+        // keys may be scoped to fewer rooms.
+        if room.private != Some(false)
+            && user
+                .as_ref()
+                .is_some_and(|user| user.authorize(room.address(), Access::Read).is_err())
+        {
+            continue;
         }
+        // Rooms created before their visibility was recorded are listed when
+        // the room lets the caller read it.
+        if room.private.is_none() {
+            let response = fetch_chatroom(
+                env.clone(),
+                room.address(),
+                chatroom_request("/members", worker::Method::Get, None, user.as_ref())?,
+            )
+            .await?;
+            if response.status_code() != 200 {
+                continue;
+            }
+        }
+        visible.push(room);
     }
 
-    async fn fetch(&mut self, mut req: worker::Request) -> Result<Response> {
-        let path = req.path();
-
-        if path == "/" {
-            Response::from_json(&None::<()>)
-        } else if path == "/messages" {
-            match req.method() {
-                worker::Method::Head => todo!(),
-                worker::Method::Get => Response::from_json(&MessageList {
-                    messages: self.messages.clone(),
-                }),
-                worker::Method::Post => {
-                    let body = req.text().await.unwrap();
-                    let new_message = serde_json::from_str::<Message>(body.as_str()).unwrap();
-                    self.messages.push(new_message);
-                    Response::from_json(&MessageList {
-                        messages: self.messages.clone(),
-                    })
-                }
-                worker::Method::Put => todo!(),
-                worker::Method::Patch => todo!(),
-                worker::Method::Delete => todo!(),
-                worker::Method::Options => todo!(),
-                worker::Method::Connect => todo!(),
-                worker::Method::Trace => todo!(),
+    Ok(axum::Json(visible))
+}
+
+/// Creates a room owned by the caller, who must be signed in rather than use
+/// an API key. A `name` makes the room addressable by that name, otherwise a
+/// unique durable object id is generated and used as the room's address.
+/// `private` and `invite_only` restrict who may read and join.
+#[worker::send]
+pub async fn post_rooms(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Json(payload): Json<CreateRoom>,
+) -> ApiResult<(axum::http::StatusCode, axum::Json<RoomInfo>)> {
+    user.require_session()?;
+
+    let namespace = env.durable_object("CHATROOM")?;
+    let mut info = match payload.name {
+        Some(name) => {
+            if !directory::is_valid_room_name(&name) {
+                return Err(AppError::BadRequest(format!("Invalid room name: {name}")));
+            }
+            if name == DEFAULT_ROOM {
+                return Err(AppError::Conflict(format!("Room {name} already exists")));
+            }
+            RoomInfo {
+                id: namespace.id_from_name(&name)?.to_string(),
+                name: Some(name),
+                private: None,
+                readers: Vec::new(),
+                created_at: Date::now().as_millis(),
             }
-        } else {
-            todo!()
         }
+        None => RoomInfo {
+            id: namespace.unique_id()?.to_string(),
+            name: None,
+            private: None,
+            readers: Vec::new(),
+            created_at: Date::now().as_millis(),
+        },
+    };
+
+    // The room refuses an owner once in use, so claim it before listing it.
+    let acl = NewAcl {
+        owner: user.name,
+        settings: payload.access,
+    };
+    let json =
+        serde_json::to_string(&acl).map_err(|error| AppError::Internal(error.to_string()))?;
+    let response = fetch_chatroom(
+        env.clone(),
+        info.address(),
+        chatroom_request("/access", worker::Method::Post, Some(json), None)?,
+    )
+    .await?;
+    let acl = read_json::<Acl>(response).await?;
+    info.private = Some(acl.private);
+    info.readers = acl.readers();
+
+    let json =
+        serde_json::to_string(&info).map_err(|error| AppError::Internal(error.to_string()))?;
+    let response = fetch_directory(
+        env,
+        chatroom_request("/rooms", worker::Method::Post, Some(json), None)?,
+    )
+    .await?;
+    let info = read_json::<RoomInfo>(response).await?;
+
+    Ok((axum::http::StatusCode::CREATED, axum::Json(info)))
+}
+
+/// The rate limits of the messages posted to a room.
+#[worker::send]
+pub async fn get_room_limits(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<RateLimits>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
     }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/limits", worker::Method::Get, None, user.as_ref())?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
 }
 
-async fn fetch_chatroom(env: Arc<Env>, req: worker::Request) -> Result<String> {
-    let chatroom = env.durable_object("CHATROOM")?;
-    let stub = chatroom.id_from_name("CHATROOM")?.get_stub()?;
+/// Replaces the rate limits of a room. Moderators and owners may do so.
+#[worker::send]
+pub async fn put_room_limits(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<RateLimits>,
+) -> ApiResult<axum::Json<RateLimits>> {
+    user.authorize(&room, Access::Write)?;
 
-    let response = stub.fetch_with_request(req).await?.text().await?;
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/limits", worker::Method::Put, Some(json), Some(&user))?,
+    )
+    .await?;
 
-    Ok(response)
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// The retention policy of a room, along with its legal hold, if any.
+#[worker::send]
+pub async fn get_room_retention(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Retention>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/retention", worker::Method::Get, None, user.as_ref())?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Replaces the retention policy of a room. Expired messages are deleted for
+/// good in the background. Owners and global moderators may do so.
+#[worker::send]
+pub async fn put_room_retention(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<RetentionPolicy>,
+) -> ApiResult<axum::Json<Retention>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/retention", worker::Method::Put, Some(json), Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Places a legal hold on a room, suspending its retention policy until
+/// lifted. Global moderators may do so.
+#[worker::send]
+pub async fn put_room_legal_hold(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<NewLegalHold>,
+) -> ApiResult<axum::Json<Retention>> {
+    user.require_session()?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            "/retention/hold",
+            worker::Method::Put,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Lifts the legal hold of a room, resuming its retention policy. Global
+/// moderators may do so.
+#[worker::send]
+pub async fn delete_room_legal_hold(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Retention>> {
+    user.require_session()?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/retention/hold", worker::Method::Delete, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// The blocklist and regex rules of a room. Moderators and owners may read
+/// them.
+#[worker::send]
+pub async fn get_room_rules(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<ContentRules>> {
+    user.authorize(&room, Access::Read)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/rules", worker::Method::Get, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Replaces the content rules of a room, which reject, mask or flag the
+/// messages they match. Moderators and owners may do so.
+#[worker::send]
+pub async fn put_room_rules(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<ContentRules>,
+) -> ApiResult<axum::Json<ContentRules>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/rules", worker::Method::Put, Some(json), Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Lists the flagged messages awaiting review by the room's moderators.
+#[worker::send]
+pub async fn get_room_moderation(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Vec<QueuedMessage>>> {
+    user.authorize(&room, Access::Read)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/moderation", worker::Method::Get, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Removes a reviewed message from the moderation queue. Deleting the message
+/// itself is done through its own route.
+#[worker::send]
+pub async fn delete_room_moderation(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path((room, id)): Path<(String, u64)>,
+) -> ApiResult<axum::http::StatusCode> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/moderation/{id}"),
+            worker::Method::Delete,
+            None,
+            Some(&user),
+        )?,
+    )
+    .await?;
+    if response.status_code() >= 400 {
+        return Err(read_error(response).await);
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Lists the members, invites and bans of a room, along with its access
+/// settings. Rooms without members answer `null`.
+#[worker::send]
+pub async fn get_room_members(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    OptionalUser(user): OptionalUser,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Option<Acl>>> {
+    if let Some(user) = &user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/members", worker::Method::Get, None, user.as_ref())?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Changes whether a room is private or invite-only. Only owners may do so.
+#[worker::send]
+pub async fn patch_room_access(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<AccessSettings>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env.clone(),
+        &room,
+        chatroom_request("/access", worker::Method::Patch, Some(json), Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Invites a user to a room, letting them join it even when it is
+/// invite-only. Moderators and owners may invite.
+#[worker::send]
+pub async fn post_room_invites(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+    Json(payload): Json<Invite>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/invites", worker::Method::Post, Some(json), Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+#[worker::send]
+pub async fn post_room_join(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/join", worker::Method::Post, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+#[worker::send]
+pub async fn post_room_leave(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(room): Path<String>,
+) -> ApiResult<axum::http::StatusCode> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request("/leave", worker::Method::Post, None, Some(&user))?,
+    )
+    .await?;
+    if response.status_code() >= 400 {
+        return Err(read_error(response).await);
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+/// Changes the role of a member. Only owners may do so.
+#[worker::send]
+pub async fn patch_room_member(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path((room, member)): Path<(String, String)>,
+    Json(payload): Json<RoleChange>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/members/{member}"),
+            worker::Method::Patch,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Kicks a member out of a room, or withdraws their invite.
+#[worker::send]
+pub async fn delete_room_member(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path((room, member)): Path<(String, String)>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/members/{member}"),
+            worker::Method::Delete,
+            None,
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Bans a user from a room, removing them if they are a member.
+#[worker::send]
+pub async fn put_room_ban(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path((room, banned)): Path<(String, String)>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/bans/{banned}"),
+            worker::Method::Put,
+            None,
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+#[worker::send]
+pub async fn delete_room_ban(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path((room, banned)): Path<(String, String)>,
+) -> ApiResult<axum::Json<Acl>> {
+    user.authorize(&room, Access::Write)?;
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/bans/{banned}"),
+            worker::Method::Delete,
+            None,
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Lists the caller's API keys.
+#[worker::send]
+pub async fn get_keys(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+) -> ApiResult<axum::Json<Vec<ApiKey>>> {
+    user.require_session()?;
+
+    let response = fetch_api_keys(
+        env,
+        chatroom_request("/keys", worker::Method::Get, None, Some(&user))?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Mints an API key acting on behalf of the caller within the given scopes.
+/// The response holds the key's token, which cannot be retrieved later.
+#[worker::send]
+pub async fn post_keys(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Json(payload): Json<NewApiKey>,
+) -> ApiResult<(axum::http::StatusCode, axum::Json<MintedApiKey>)> {
+    user.require_session()?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_api_keys(
+        env,
+        chatroom_request("/keys", worker::Method::Post, Some(json), Some(&user))?,
+    )
+    .await?;
+
+    Ok((
+        axum::http::StatusCode::CREATED,
+        axum::Json(read_json(response).await?),
+    ))
+}
+
+/// Replaces the scopes of one of the caller's API keys.
+#[worker::send]
+pub async fn patch_key(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(id): Path<String>,
+    Json(payload): Json<ApiKeyUpdate>,
+) -> ApiResult<axum::Json<ApiKey>> {
+    user.require_session()?;
+
+    let json = serde_json::to_string(&payload).map_err(AppError::bad_request)?;
+    let response = fetch_api_keys(
+        env,
+        chatroom_request(
+            &format!("/keys/{id}"),
+            worker::Method::Patch,
+            Some(json),
+            Some(&user),
+        )?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+/// Revokes one of the caller's API keys.
+#[worker::send]
+pub async fn delete_key(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    user: User,
+    Path(id): Path<String>,
+) -> ApiResult<axum::http::StatusCode> {
+    user.require_session()?;
+
+    let response = fetch_api_keys(
+        env,
+        chatroom_request(
+            &format!("/keys/{id}"),
+            worker::Method::Delete,
+            None,
+            Some(&user),
+        )?,
+    )
+    .await?;
+    if response.status_code() >= 400 {
+        return Err(read_error(response).await);
+    }
+
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+#[worker::send]
+pub async fn register(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> ApiResult<(axum::http::StatusCode, axum::Json<serde_json::Value>)> {
+    let json = serde_json::to_string(&credentials).map_err(AppError::bad_request)?;
+    let response = fetch_accounts(
+        env,
+        chatroom_request("/register", worker::Method::Post, Some(json), None)?,
+    )
+    .await?;
+
+    Ok((
+        axum::http::StatusCode::CREATED,
+        axum::Json(read_json(response).await?),
+    ))
+}
+
+/// Exchanges a user name and password for a session token, which is both
+/// returned and set as the session cookie.
+#[worker::send]
+pub async fn login(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    Json(credentials): Json<Credentials>,
+) -> ApiResult<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    let secret = auth::session_secret(&env)?;
+    let json = serde_json::to_string(&credentials).map_err(AppError::bad_request)?;
+    let response = fetch_accounts(
+        env,
+        chatroom_request("/verify", worker::Method::Post, Some(json), None)?,
+    )
+    .await?;
+    read_json::<serde_json::Value>(response).await?;
+
+    let session = auth::issue_token(&secret, &credentials.name, auth::now())?;
+
+    Ok((
+        [(
+            axum::http::header::SET_COOKIE,
+            auth::session_cookie_header(Some(&session)),
+        )],
+        axum::Json(session),
+    )
+        .into_response())
+}
+
+/// Starts a sign-in through the OpenID Connect provider by redirecting to it.
+#[worker::send]
+pub async fn oidc_login(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+) -> ApiResult<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    let config = oidc::OidcConfig::from_env(&env)?;
+    let secret = auth::session_secret(&env)?;
+    let discovery = oidc::discovery(&config).await?;
+
+    let login = oidc::LoginState::new(auth::now())?;
+    let location = oidc::authorization_url(&config, &discovery, &login)?;
+
+    Ok((
+        axum::http::StatusCode::FOUND,
+        [
+            (axum::http::header::LOCATION, location),
+            (
+                axum::http::header::SET_COOKIE,
+                auth::cookie_header(
+                    oidc::LOGIN_COOKIE,
+                    Some(&auth::sign(&secret, &login)?),
+                    oidc::LOGIN_TTL,
+                ),
+            ),
+        ],
+    )
+        .into_response())
+}
+
+/// Completes a sign-in through the OpenID Connect provider, setting the
+/// session cookie of the signed in user and redirecting to the app.
+#[worker::send]
+pub async fn oidc_callback(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    headers: axum::http::HeaderMap,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> ApiResult<axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    let config = oidc::OidcConfig::from_env(&env)?;
+    let secret = auth::session_secret(&env)?;
+    let now = auth::now();
+
+    let params = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect::<std::collections::HashMap<_, _>>();
+    if let Some(error) = params.get("error") {
+        let description = params.get("error_description").unwrap_or(error);
+        return Err(AppError::Unauthorized(format!(
+            "Sign-in was refused: {description}"
+        )));
+    }
+    let (Some(code), Some(state)) = (params.get("code"), params.get("state")) else {
+        return Err(AppError::BadRequest(
+            "Missing code or state parameter".to_string(),
+        ));
+    };
+
+    let login = auth::cookie(&headers, oidc::LOGIN_COOKIE)
+        .ok_or_else(|| AppError::Unauthorized("No sign-in in progress".to_string()))
+        .and_then(|cookie| auth::verify::<oidc::LoginState>(&secret, cookie))?;
+    if login.exp <= now || !auth::constant_time_eq(login.state.as_bytes(), state.as_bytes()) {
+        return Err(AppError::Unauthorized(
+            "Sign-in expired or was not started here".to_string(),
+        ));
+    }
+
+    let discovery = oidc::discovery(&config).await?;
+    let id_token = oidc::exchange_code(&config, &discovery, code, &login.verifier).await?;
+    let claims = oidc::verify_id_token(&config, &discovery, &id_token, &login.nonce, now).await?;
+
+    let session = auth::issue_token(&secret, &claims.user_name()?, now)?;
+
+    let mut response = (
+        axum::http::StatusCode::FOUND,
+        [(axum::http::header::LOCATION, "/")],
+    )
+        .into_response();
+    for cookie in [
+        auth::session_cookie_header(Some(&session)),
+        auth::cookie_header(oidc::LOGIN_COOKIE, None, 0),
+    ] {
+        response.headers_mut().append(
+            axum::http::header::SET_COOKIE,
+            axum::http::HeaderValue::from_str(&cookie)
+                .map_err(|error| AppError::Internal(error.to_string()))?,
+        );
+    }
+
+    Ok(response)
+}
+
+/// Clears the session cookie. Tokens themselves stay valid until they expire.
+pub async fn logout() -> impl axum::response::IntoResponse {
+    (
+        axum::http::StatusCode::NO_CONTENT,
+        [(
+            axum::http::header::SET_COOKIE,
+            auth::session_cookie_header(None::<&Session>),
+        )],
+    )
+}
+
+#[worker::send]
+pub async fn root(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+) -> ApiResult<axum::Json<Option<()>>> {
+    let response = fetch_chatroom(
+        env,
+        DEFAULT_ROOM,
+        worker::Request::new("http://fake-host/", worker::Method::Get)?,
+    )
+    .await?;
+
+    Ok(axum::Json(read_json(response).await?))
+}
+
+pub async fn not_found() -> AppError {
+    AppError::NotFound("No such route".to_string())
+}
+
+/// Appends a forwarded query string to a durable object path.
+fn with_query(path: &str, query: Option<String>) -> String {
+    match query {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    }
+}
+
+/// Builds a request addressed to a durable object, made on behalf of `user`.
+fn chatroom_request(
+    path: &str,
+    method: worker::Method,
+    body: Option<String>,
+    user: Option<&User>,
+) -> Result<Request> {
+    let mut headers = worker::Headers::new();
+    if let Some(user) = user {
+        headers.set(auth::USER_HEADER, &user.name)?;
+    }
+
+    worker::Request::new_with_init(
+        &format!("http://fake-host{path}"),
+        RequestInit::new()
+            .with_method(method)
+            .with_headers(headers)
+            .with_body(body.map(|body| JsValue::from_str(&body))),
+    )
+}
+
+/// Resolves a room to its durable object. Rooms created without a name are
+/// addressed by their hex id, every other room by name.
+fn chatroom_stub(env: &Env, room: &str) -> Result<Stub> {
+    let chatroom = env.durable_object("CHATROOM")?;
+    let id = if directory::is_unique_id(room) {
+        chatroom.id_from_string(room)?
+    } else {
+        chatroom.id_from_name(room)?
+    };
+
+    id.get_stub()
+}
+
+async fn fetch_chatroom(
+    env: Arc<Env>,
+    room: &str,
+    mut req: worker::Request,
+) -> ApiResult<Response> {
+    let stub = chatroom_stub(&env, room).map_err(AppError::bad_gateway)?;
+    req.headers_mut()?.set(auth::ROOM_HEADER, room)?;
+
+    stub.fetch_with_request(req)
+        .await
+        .map_err(AppError::bad_gateway)
+}
+
+/// Looks up an attachment for `user`, through the room it was uploaded to as
+/// recorded on the R2 object.
+async fn find_attachment(
+    env: Arc<Env>,
+    user: Option<&User>,
+    id: &str,
+) -> ApiResult<(Bucket, Attachment)> {
+    let not_found = || AppError::NotFound(format!("No attachment with id {id}"));
+    if !attachments::is_valid_id(id) {
+        return Err(not_found());
+    }
+
+    let bucket = env.bucket(attachments::BUCKET)?;
+    let room = bucket
+        .head(attachments::object_key(id))
+        .await?
+        .ok_or_else(not_found)?
+        .custom_metadata()?
+        .remove(attachments::ROOM_METADATA)
+        .ok_or_else(not_found)?;
+    if let Some(user) = user {
+        user.authorize(&room, Access::Read)?;
+    }
+
+    let response = fetch_chatroom(
+        env,
+        &room,
+        chatroom_request(
+            &format!("/attachments/{id}"),
+            worker::Method::Get,
+            None,
+            user,
+        )?,
+    )
+    .await?;
+
+    Ok((bucket, read_json(response).await?))
+}
+
+async fn fetch_directory(env: Arc<Env>, req: worker::Request) -> ApiResult<Response> {
+    let directory = env.durable_object("ROOM_DIRECTORY")?;
+    let stub = directory
+        .id_from_name("ROOM_DIRECTORY")?
+        .get_stub()
+        .map_err(AppError::bad_gateway)?;
+
+    stub.fetch_with_request(req)
+        .await
+        .map_err(AppError::bad_gateway)
+}
+
+async fn fetch_accounts(env: Arc<Env>, req: worker::Request) -> ApiResult<Response> {
+    let accounts = env.durable_object("ACCOUNTS")?;
+    let stub = accounts
+        .id_from_name("ACCOUNTS")?
+        .get_stub()
+        .map_err(AppError::bad_gateway)?;
+
+    stub.fetch_with_request(req)
+        .await
+        .map_err(AppError::bad_gateway)
+}
+
+async fn fetch_api_keys(env: Arc<Env>, req: worker::Request) -> ApiResult<Response> {
+    let api_keys = env.durable_object("API_KEYS")?;
+    let stub = api_keys
+        .id_from_name("API_KEYS")?
+        .get_stub()
+        .map_err(AppError::bad_gateway)?;
+
+    stub.fetch_with_request(req)
+        .await
+        .map_err(AppError::bad_gateway)
+}
+
+/// Reads the JSON body of a successful durable object response.
+async fn read_json<T: serde::de::DeserializeOwned>(mut response: Response) -> ApiResult<T> {
+    if response.status_code() >= 400 {
+        return Err(read_error(response).await);
+    }
+
+    let body = response.text().await.map_err(AppError::bad_gateway)?;
+    serde_json::from_str(&body).map_err(AppError::bad_gateway)
+}
+
+/// Reads the problem document of a durable object error response.
+async fn read_error(mut response: Response) -> AppError {
+    let status = response.status_code();
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(error) => return AppError::bad_gateway(error),
+    };
+
+    match serde_json::from_str::<Problem>(&body) {
+        Ok(problem) => AppError::Upstream(Box::new(problem)),
+        Err(_) => AppError::BadGateway(format!("Room responded with {status}: {body}")),
+    }
 }
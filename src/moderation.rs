@@ -0,0 +1,184 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiResult, AppError};
+use crate::validation::{InvalidParam, Validator};
+
+const MAX_RULES: usize = 200;
+
+/// What a content policy decided about a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Post the message with the given content instead.
+    Mask(String),
+    /// Post the message, but queue it for review by the moderators.
+    Flag(String),
+    Reject(String),
+}
+
+/// A check run on every message posted or edited in a room. Implement it to
+/// plug in a classifier, and register it in [`policies`].
+#[async_trait::async_trait(?Send)]
+pub trait ContentPolicy {
+    async fn evaluate(&self, author: &str, content: &str) -> ApiResult<Verdict>;
+}
+
+/// The result of running a message through the policies of a room.
+pub struct Review {
+    pub content: String,
+    /// Reasons the message was flagged for, empty if it was not.
+    pub flags: Vec<String>,
+}
+
+/// Runs the policies in order. Masks apply to the content seen by later
+/// policies, and the first rejection stops the pipeline.
+pub async fn review(
+    policies: &[Box<dyn ContentPolicy>],
+    author: &str,
+    content: String,
+) -> ApiResult<Review> {
+    let mut review = Review {
+        content,
+        flags: Vec::new(),
+    };
+
+    for policy in policies {
+        match policy.evaluate(author, &review.content).await? {
+            Verdict::Allow => {}
+            Verdict::Mask(content) => review.content = content,
+            Verdict::Flag(reason) => review.flags.push(reason),
+            Verdict::Reject(reason) => {
+                return Err(AppError::Validation(vec![InvalidParam {
+                    name: "content".to_string(),
+                    reason,
+                }]))
+            }
+        }
+    }
+
+    Ok(review)
+}
+
+/// The policies applied to a room: its rules, followed by any custom
+/// classifiers, which are added to the list here.
+pub fn policies(rules: ContentRules) -> ApiResult<Vec<Box<dyn ContentPolicy>>> {
+    rules.compile()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Reject,
+    /// Replaces each match by asterisks.
+    Mask,
+    Flag,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PatternKind {
+    /// A word or phrase, matched case-insensitively on word boundaries.
+    #[default]
+    Word,
+    Regex,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub pattern: String,
+    #[serde(default)]
+    pub kind: PatternKind,
+    pub action: Action,
+}
+
+/// The blocklist and regex rules of a room, configured by its moderators.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ContentRules {
+    pub rules: Vec<Rule>,
+}
+
+impl ContentRules {
+    /// Checks the rules compile, failing with the invalid ones.
+    pub fn validate(&self) -> ApiResult<()> {
+        let mut validator = Validator::default();
+        if self.rules.len() > MAX_RULES {
+            validator.invalid("rules", format!("must hold at most {MAX_RULES} rules"));
+        }
+        for (index, rule) in self.rules.iter().enumerate() {
+            if let Err(error) = rule.regex() {
+                validator.invalid(&format!("rules[{index}].pattern"), error);
+            }
+        }
+
+        validator.finish()
+    }
+
+    fn compile(self) -> ApiResult<Vec<Box<dyn ContentPolicy>>> {
+        self.rules
+            .into_iter()
+            .map(|rule| {
+                let policy = RulePolicy {
+                    regex: rule.regex().map_err(AppError::Internal)?,
+                    action: rule.action,
+                };
+                Ok(Box::new(policy) as Box<dyn ContentPolicy>)
+            })
+            .collect()
+    }
+}
+
+impl Rule {
+    fn regex(&self) -> Result<Regex, String> {
+        if self.pattern.trim().is_empty() {
+            return Err("must not be empty".to_string());
+        }
+
+        let pattern = match self.kind {
+            PatternKind::Word => format!(r"\b{}\b", regex::escape(self.pattern.trim())),
+            PatternKind::Regex => self.pattern.clone(),
+        };
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.kind == PatternKind::Word)
+            .size_limit(1 << 16)
+            .build()
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// A compiled [`Rule`], applied as a policy of its own.
+struct RulePolicy {
+    regex: Regex,
+    action: Action,
+}
+
+#[async_trait::async_trait(?Send)]
+impl ContentPolicy for RulePolicy {
+    async fn evaluate(&self, _author: &str, content: &str) -> ApiResult<Verdict> {
+        let Some(found) = self.regex.find(content) else {
+            return Ok(Verdict::Allow);
+        };
+
+        Ok(match self.action {
+            Action::Reject => Verdict::Reject("is not allowed in this room".to_string()),
+            Action::Flag => Verdict::Flag(format!("matched {}", found.as_str())),
+            Action::Mask => Verdict::Mask(
+                self.regex
+                    .replace_all(content, |captures: &regex::Captures| {
+                        "*".repeat(captures[0].chars().count())
+                    })
+                    .into_owned(),
+            ),
+        })
+    }
+}
+
+/// A message awaiting review by the moderators.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FlaggedMessage {
+    pub message_id: u64,
+    pub author: String,
+    pub reasons: Vec<String>,
+    pub flagged_at: u64,
+}
@@ -0,0 +1,397 @@
+//! Sign-in through an OpenID Connect identity provider, using the
+//! authorization code flow with PKCE.
+//!
+//! The provider is configured with the `OIDC_ISSUER`, `OIDC_CLIENT_ID` and
+//! `OIDC_REDIRECT_URI` variables, and the optional `OIDC_CLIENT_SECRET` secret
+//! for confidential clients. Its discovery document and signing keys are cached
+//! for the lifetime of the isolate.
+
+use std::cell::RefCell;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::signature::Verifier;
+use rsa::{BigUint, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::{Env, Fetch, Headers, Method, Request, RequestInit, Response};
+
+use crate::auth;
+use crate::error::{ApiResult, AppError};
+
+/// Cookie holding the [`LoginState`] between the redirect to the provider and
+/// the callback.
+pub const LOGIN_COOKIE: &str = "oidc_login";
+
+/// Seconds a user has to complete the sign-in at the provider.
+pub const LOGIN_TTL: u64 = 10 * 60;
+
+/// Seconds the discovery document and signing keys are cached for.
+const CACHE_TTL: u64 = 60 * 60;
+
+const SCOPE: &str = "openid email profile";
+
+thread_local! {
+    static DISCOVERY: RefCell<Option<Cached<Discovery>>> = const { RefCell::new(None) };
+    static JWKS: RefCell<Option<Cached<Jwks>>> = const { RefCell::new(None) };
+}
+
+struct Cached<T> {
+    fetched_at: u64,
+    value: T,
+}
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+}
+
+impl OidcConfig {
+    /// Reads the provider configuration, answering 404 when sign-in through a
+    /// provider is not set up.
+    pub fn from_env(env: &Env) -> ApiResult<Self> {
+        let var = |name: &str| {
+            env.var(name)
+                .map(|value| value.to_string())
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        let (Some(issuer), Some(client_id), Some(redirect_uri)) = (
+            var("OIDC_ISSUER"),
+            var("OIDC_CLIENT_ID"),
+            var("OIDC_REDIRECT_URI"),
+        ) else {
+            return Err(AppError::NotFound(
+                "OIDC login is not configured".to_string(),
+            ));
+        };
+
+        Ok(OidcConfig {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: env.secret("OIDC_CLIENT_SECRET").ok().map(|s| s.to_string()),
+            redirect_uri,
+        })
+    }
+}
+
+/// The parts of the provider's discovery document used here.
+#[derive(Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+}
+
+/// What the callback needs to finish a sign-in, kept in a signed cookie.
+#[derive(Serialize, Deserialize)]
+pub struct LoginState {
+    pub state: String,
+    /// The PKCE code verifier.
+    pub verifier: String,
+    pub nonce: String,
+    pub exp: u64,
+}
+
+impl LoginState {
+    pub fn new(now: u64) -> ApiResult<Self> {
+        Ok(LoginState {
+            state: auth::random_token(16)?,
+            verifier: auth::random_token(32)?,
+            nonce: auth::random_token(16)?,
+            exp: now + LOGIN_TTL,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims of an ID token.
+#[derive(Deserialize)]
+pub struct IdClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audience,
+    pub exp: u64,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: Option<bool>,
+}
+
+impl IdClaims {
+    /// The chat identity of the signed in user: their verified email address,
+    /// or else their subject scoped to the provider's host. Either contains an
+    /// `@`, which names registered with a password cannot.
+    pub fn user_name(&self) -> ApiResult<String> {
+        let name = match (&self.email, self.email_verified) {
+            (Some(email), Some(true)) => email.to_lowercase(),
+            _ => {
+                let host = worker::Url::parse(&self.iss)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default();
+                format!("{}@{host}", self.sub)
+            }
+        };
+
+        if !auth::is_valid_user_name(&name) {
+            return Err(AppError::Forbidden(format!(
+                "{name} cannot be used as a user name"
+            )));
+        }
+
+        Ok(name)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(audience) => audience == client_id,
+            Audience::Many(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+}
+
+/// The PKCE `S256` challenge of a code verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// The provider URL a user is sent to in order to sign in.
+pub fn authorization_url(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    login: &LoginState,
+) -> ApiResult<String> {
+    let url = worker::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_uri),
+            ("scope", SCOPE),
+            ("state", &login.state),
+            ("nonce", &login.nonce),
+            ("code_challenge", &pkce_challenge(&login.verifier)),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(AppError::bad_gateway)?;
+
+    Ok(url.to_string())
+}
+
+/// The provider's discovery document, fetched from its well-known location.
+pub async fn discovery(config: &OidcConfig) -> ApiResult<Discovery> {
+    let now = auth::now();
+    if let Some(discovery) = DISCOVERY.with_borrow(|cached| fresh(cached, now)) {
+        return Ok(discovery);
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let discovery = fetch_json::<Discovery>(Request::new(&url, Method::Get)?).await?;
+    if discovery.issuer.trim_end_matches('/') != config.issuer {
+        return Err(AppError::BadGateway(format!(
+            "Provider reported issuer {}, expected {}",
+            discovery.issuer, config.issuer
+        )));
+    }
+
+    DISCOVERY.set(Some(Cached {
+        fetched_at: now,
+        value: discovery.clone(),
+    }));
+
+    Ok(discovery)
+}
+
+/// Exchanges an authorization code for the user's ID token.
+pub async fn exchange_code(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    code: &str,
+    verifier: &str,
+) -> ApiResult<String> {
+    let mut form = url::form_urlencoded::Serializer::new(String::new());
+    form.append_pair("grant_type", "authorization_code")
+        .append_pair("code", code)
+        .append_pair("redirect_uri", &config.redirect_uri)
+        .append_pair("client_id", &config.client_id)
+        .append_pair("code_verifier", verifier);
+    if let Some(secret) = &config.client_secret {
+        form.append_pair("client_secret", secret);
+    }
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+    headers.set("Accept", "application/json")?;
+    let req = Request::new_with_init(
+        &discovery.token_endpoint,
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(form.finish().into())),
+    )?;
+
+    Ok(fetch_json::<TokenResponse>(req).await?.id_token)
+}
+
+/// Checks the signature and claims of an ID token, returning its claims.
+pub async fn verify_id_token(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    token: &str,
+    nonce: &str,
+    now: u64,
+) -> ApiResult<IdClaims> {
+    let invalid = |detail: &str| AppError::Unauthorized(format!("Invalid ID token: {detail}"));
+
+    let (signing_input, signature) = token.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+    let (header, payload) = signing_input
+        .split_once('.')
+        .ok_or_else(|| invalid("malformed"))?;
+
+    let header = URL_SAFE_NO_PAD
+        .decode(header)
+        .ok()
+        .and_then(|header| serde_json::from_slice::<serde_json::Value>(&header).ok())
+        .ok_or_else(|| invalid("malformed header"))?;
+    if header["alg"] != "RS256" {
+        return Err(invalid("unsupported algorithm"));
+    }
+    let kid = header["kid"].as_str();
+
+    let key = match signing_key(discovery, kid, now, false).await? {
+        Some(key) => key,
+        // The provider may have rotated its keys since they were cached.
+        None => signing_key(discovery, kid, now, true)
+            .await?
+            .ok_or_else(|| invalid("unknown signing key"))?,
+    };
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|signature| Signature::try_from(signature.as_slice()).ok())
+        .ok_or_else(|| invalid("malformed signature"))?;
+    VerifyingKey::<Sha256>::new(key)
+        .verify(signing_input.as_bytes(), &signature)
+        .map_err(|_| invalid("bad signature"))?;
+
+    let claims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|payload| serde_json::from_slice::<IdClaims>(&payload).ok())
+        .ok_or_else(|| invalid("malformed claims"))?;
+    if claims.iss != discovery.issuer {
+        return Err(invalid("wrong issuer"));
+    }
+    if !claims.aud.contains(&config.client_id) {
+        return Err(invalid("wrong audience"));
+    }
+    if claims.exp <= now {
+        return Err(invalid("expired"));
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(invalid("wrong nonce"));
+    }
+
+    Ok(claims)
+}
+
+/// Looks up an RSA signing key of the provider, refetching the key set when
+/// `refresh` is set or the cached one is stale.
+async fn signing_key(
+    discovery: &Discovery,
+    kid: Option<&str>,
+    now: u64,
+    refresh: bool,
+) -> ApiResult<Option<RsaPublicKey>> {
+    let jwks = match JWKS.with_borrow(|cached| fresh(cached, now)) {
+        Some(jwks) if !refresh => jwks,
+        _ => {
+            let jwks = fetch_json::<Jwks>(Request::new(&discovery.jwks_uri, Method::Get)?).await?;
+            JWKS.set(Some(Cached {
+                fetched_at: now,
+                value: jwks.clone(),
+            }));
+            jwks
+        }
+    };
+
+    let Some(jwk) = jwks
+        .keys
+        .iter()
+        .find(|jwk| jwk.kty == "RSA" && (kid.is_none() || jwk.kid.as_deref() == kid))
+    else {
+        return Ok(None);
+    };
+
+    let decode = |value: &Option<String>| {
+        value
+            .as_deref()
+            .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+            .map(|bytes| BigUint::from_bytes_be(&bytes))
+            .ok_or_else(|| AppError::bad_gateway("Malformed signing key"))
+    };
+    let key = RsaPublicKey::new(decode(&jwk.n)?, decode(&jwk.e)?).map_err(AppError::bad_gateway)?;
+
+    Ok(Some(key))
+}
+
+fn fresh<T: Clone>(cached: &Option<Cached<T>>, now: u64) -> Option<T> {
+    cached
+        .as_ref()
+        .filter(|cached| now < cached.fetched_at + CACHE_TTL)
+        .map(|cached| cached.value.clone())
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(req: Request) -> ApiResult<T> {
+    let url = req.url()?;
+    let mut response: Response = Fetch::Request(req)
+        .send()
+        .await
+        .map_err(AppError::bad_gateway)?;
+    let body = response.text().await.map_err(AppError::bad_gateway)?;
+    if response.status_code() >= 400 {
+        return Err(AppError::BadGateway(format!(
+            "{url} responded with {}: {body}",
+            response.status_code()
+        )));
+    }
+
+    serde_json::from_str(&body).map_err(AppError::bad_gateway)
+}
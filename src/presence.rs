@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+/// Storage key prefix of the presence records, followed by the user's name.
+pub const PRESENCE_PREFIX: &str = "presence:";
+
+/// Milliseconds without a heartbeat after which a user is no longer online.
+/// Clients are expected to send a heartbeat about twice as often.
+pub const PRESENCE_TTL: u64 = 60_000;
+
+/// Milliseconds a typing notification lasts, unless renewed.
+pub const TYPING_TTL: u64 = 5_000;
+
+/// A user online in a room.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Presence {
+    pub user: String,
+    /// When the user came online, in milliseconds since the Unix epoch.
+    pub since: u64,
+    pub last_seen: u64,
+    /// Set while the user is typing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typing_until: Option<u64>,
+    /// Open WebSockets of the user. Users only reached through heartbeats
+    /// over HTTP have none.
+    #[serde(default)]
+    pub connections: u32,
+}
+
+impl Presence {
+    pub fn new(user: &str, now: u64) -> Self {
+        Presence {
+            user: user.to_string(),
+            since: now,
+            last_seen: now,
+            typing_until: None,
+            connections: 0,
+        }
+    }
+
+    pub fn is_stale(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_seen) > PRESENCE_TTL
+    }
+
+    /// Whether the user is still online: through an open WebSocket, or a
+    /// recent heartbeat.
+    pub fn is_online(&self, now: u64) -> bool {
+        self.connections > 0 || !self.is_stale(now)
+    }
+
+    pub fn is_typing(&self, now: u64) -> bool {
+        self.typing_until.is_some_and(|until| until > now)
+    }
+}
+
+pub fn presence_key(user: &str) -> String {
+    format!("{PRESENCE_PREFIX}{user}")
+}
+
+/// A frame sent by a client over a WebSocket other than a new message.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientSignal {
+    /// Keeps the user online.
+    Heartbeat,
+    /// Tells the room the user is typing.
+    Typing,
+}
@@ -0,0 +1,308 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiResult, AppError};
+use crate::AppState;
+
+/// Header carrying the client's address, as set by Cloudflare.
+const CLIENT_IP_HEADER: &str = "CF-Connecting-IP";
+
+const DEFAULT_IP_LIMIT_PER_MINUTE: u32 = 600;
+
+/// Number of per-address buckets kept in each worker isolate.
+const MAX_IP_BUCKETS: usize = 10_000;
+
+/// Milliseconds after its last refill before a bucket is forgotten.
+const IDLE_BUCKET_TTL: u64 = 10 * 60 * 1000;
+
+/// Milliseconds between sweeps of the idle buckets, unless full before.
+const SWEEP_INTERVAL: u64 = 60 * 1000;
+
+thread_local! {
+    static IP_BUCKETS: RefCell<Buckets> = RefCell::new(Buckets::new(MAX_IP_BUCKETS));
+}
+
+/// A token bucket holding up to `burst` tokens, refilled with `per_minute`
+/// tokens a minute.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn validate(&self, name: &str) -> ApiResult<()> {
+        if self.burst == 0 || self.per_minute == 0 {
+            return Err(AppError::BadRequest(format!(
+                "The {name} limit needs a positive `burst` and `per_minute`"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Rate limits of the messages posted to a room.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct RateLimits {
+    /// Limit of each author.
+    pub author: Limit,
+    /// Limit of the room as a whole.
+    pub room: Limit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            author: Limit {
+                burst: 10,
+                per_minute: 30,
+            },
+            room: Limit {
+                burst: 60,
+                per_minute: 300,
+            },
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn validate(&self) -> ApiResult<()> {
+        self.author.validate("author")?;
+        self.room.validate("room")
+    }
+}
+
+pub struct TokenBucket {
+    tokens: f64,
+    /// Milliseconds since the Unix epoch.
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Limit, now: u64) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: Limit, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens =
+            (self.tokens + elapsed * limit.per_minute as f64 / 60_000.0).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+
+    /// Refills the bucket, then checks that a token is available without
+    /// taking it.
+    pub fn check(&mut self, limit: Limit, now: u64) -> ApiResult<()> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            return Ok(());
+        }
+
+        let missing_ms = (1.0 - self.tokens) * 60_000.0 / limit.per_minute as f64;
+        Err(AppError::TooManyRequests {
+            retry_after: (missing_ms / 1000.0).ceil().max(1.0) as u64,
+        })
+    }
+
+    /// Takes a token, which [`TokenBucket::check`] must have found available.
+    pub fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&self, limit: Limit, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens + elapsed * limit.per_minute as f64 / 60_000.0 >= limit.burst as f64
+    }
+}
+
+/// Token buckets by key, e.g. by author, holding at most `max` buckets.
+pub struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+    max: usize,
+    /// Milliseconds since the Unix epoch.
+    swept_at: u64,
+}
+
+impl Buckets {
+    pub fn new(max: usize) -> Self {
+        Buckets {
+            buckets: HashMap::new(),
+            max,
+            swept_at: 0,
+        }
+    }
+
+    /// The bucket of `key`, created full when missing.
+    pub fn get(&mut self, key: &str, limit: Limit, now: u64) -> &mut TokenBucket {
+        if !self.buckets.contains_key(key) {
+            self.sweep(limit, now);
+        }
+
+        self.buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(limit, now))
+    }
+
+    /// Forgets the buckets that refilled completely, as new ones start out
+    /// full, and those not refilled for `IDLE_BUCKET_TTL`. If still at `max`,
+    /// the least recently refilled ones make room too.
+    fn sweep(&mut self, limit: Limit, now: u64) {
+        if self.buckets.len() < self.max && now.saturating_sub(self.swept_at) < SWEEP_INTERVAL {
+            return;
+        }
+        self.swept_at = now;
+
+        self.buckets.retain(|_, bucket| {
+            now.saturating_sub(bucket.updated_at) < IDLE_BUCKET_TTL && !bucket.is_full(limit, now)
+        });
+
+        if self.buckets.len() >= self.max {
+            let mut by_age = self
+                .buckets
+                .iter()
+                .map(|(key, bucket)| (bucket.updated_at, key.clone()))
+                .collect::<Vec<_>>();
+            by_age.sort_unstable();
+            for (_, key) in &by_age[..=self.buckets.len() - self.max] {
+                self.buckets.remove(key);
+            }
+        }
+    }
+}
+
+/// Coarse limit of the requests of each client address, configured with the
+/// `IP_RATE_LIMIT_PER_MINUTE` variable. Buckets are kept in the memory of the
+/// worker isolate, so clients spread across isolates get more.
+#[worker::send]
+pub async fn limit_ip(
+    axum::extract::State(AppState { env }): axum::extract::State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> ApiResult<axum::response::Response> {
+    let Some(ip) = req
+        .headers()
+        .get(CLIENT_IP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+    else {
+        return Ok(next.run(req).await);
+    };
+
+    let per_minute = env
+        .var("IP_RATE_LIMIT_PER_MINUTE")
+        .ok()
+        .and_then(|value| value.to_string().trim().parse().ok())
+        .filter(|per_minute| *per_minute > 0)
+        .unwrap_or(DEFAULT_IP_LIMIT_PER_MINUTE);
+    let limit = Limit {
+        burst: per_minute,
+        per_minute,
+    };
+    let now = worker::Date::now().as_millis();
+
+    IP_BUCKETS.with_borrow_mut(|buckets| {
+        let bucket = buckets.get(&ip, limit, now);
+        bucket.check(limit, now)?;
+        bucket.take();
+
+        Ok::<_, AppError>(())
+    })?;
+
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        burst: 2,
+        per_minute: 6,
+    };
+
+    #[test]
+    fn takes_tokens_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(LIMIT, 0);
+        for _ in 0..2 {
+            bucket.check(LIMIT, 0).unwrap();
+            bucket.take();
+        }
+
+        assert!(matches!(
+            bucket.check(LIMIT, 0),
+            Err(AppError::TooManyRequests { retry_after: 10 })
+        ));
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(LIMIT, 0);
+        bucket.take();
+        bucket.take();
+
+        assert!(bucket.check(LIMIT, 5_000).is_err());
+        bucket.check(LIMIT, 10_000).unwrap();
+        assert!(!bucket.is_full(LIMIT, 10_000));
+        assert!(bucket.is_full(LIMIT, 20_000));
+
+        bucket.check(LIMIT, 3_600_000).unwrap();
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn checking_does_not_take_a_token() {
+        let mut bucket = TokenBucket::new(LIMIT, 0);
+        for _ in 0..5 {
+            bucket.check(LIMIT, 0).unwrap();
+        }
+
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn forgets_full_and_idle_buckets() {
+        // Refills a token a minute, so buckets stay far from full.
+        let slow = Limit {
+            burst: 100,
+            per_minute: 1,
+        };
+        let mut buckets = Buckets::new(100);
+        buckets.get("full", slow, 0);
+        for key in ["idle", "busy"] {
+            let bucket = buckets.get(key, slow, 0);
+            for _ in 0..50 {
+                bucket.take();
+            }
+        }
+
+        let busy = buckets.get("busy", slow, IDLE_BUCKET_TTL - 1000);
+        busy.check(slow, IDLE_BUCKET_TTL - 1000).unwrap();
+        buckets.get("new", slow, IDLE_BUCKET_TTL + 1000);
+
+        let mut keys = buckets.buckets.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["busy", "new"]);
+    }
+
+    #[test]
+    fn forgets_the_least_recently_refilled_buckets_at_the_cap() {
+        let mut buckets = Buckets::new(3);
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            let bucket = buckets.get(key, LIMIT, i as u64);
+            bucket.take();
+            bucket.take();
+        }
+
+        buckets.get("d", LIMIT, 3);
+
+        assert_eq!(buckets.buckets.len(), 3);
+        assert!(!buckets.buckets.contains_key("a"));
+        assert!(buckets.buckets.contains_key("d"));
+    }
+}
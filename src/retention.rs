@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::chatroom::Message;
+use crate::error::ApiResult;
+use crate::validation::Validator;
+
+/// Messages deleted by each run of the alarm.
+pub const PRUNE_BATCH: usize = 100;
+
+/// Milliseconds between checks of a room with a retention policy, once caught
+/// up.
+pub const CHECK_INTERVAL: u64 = 60 * 60 * 1000;
+
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+const MAX_AGE_DAYS: u32 = 36_500;
+const MAX_REASON_LENGTH: usize = 500;
+
+/// How long a room keeps its messages, configured by its owners. Messages
+/// older than `max_age_days`, and those beyond the newest `keep_last`, are
+/// deleted for good. Messages are kept forever when neither is set.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub max_age_days: Option<u32>,
+    #[serde(default)]
+    pub keep_last: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> ApiResult<()> {
+        let mut validator = Validator::default();
+        if self
+            .max_age_days
+            .is_some_and(|days| !(1..=MAX_AGE_DAYS).contains(&days))
+        {
+            validator.invalid(
+                "max_age_days",
+                format!("must be between 1 and {MAX_AGE_DAYS}"),
+            );
+        }
+        if self.keep_last == Some(0) {
+            validator.invalid("keep_last", "must be positive");
+        }
+
+        validator.finish()
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.max_age_days.is_some() || self.keep_last.is_some()
+    }
+
+    /// Number of messages to delete from the start of `messages`, which are
+    /// ordered oldest first. Tombstones do not count towards `keep_last`.
+    pub fn expired(&self, messages: &[Message], now: u64) -> usize {
+        let aged = self.max_age_days.map_or(0, |days| {
+            let cutoff = now.saturating_sub(u64::from(days) * DAY);
+            messages
+                .iter()
+                .take_while(|message| message.timestamp < cutoff)
+                .count()
+        });
+
+        let live = messages
+            .iter()
+            .filter(|message| !message.is_deleted())
+            .count();
+        let mut excess = self
+            .keep_last
+            .map_or(0, |keep| live.saturating_sub(keep as usize));
+        let surplus = messages
+            .iter()
+            .take_while(|message| {
+                if excess == 0 {
+                    return false;
+                }
+                if !message.is_deleted() {
+                    excess -= 1;
+                }
+                true
+            })
+            .count();
+
+        aged.max(surplus)
+    }
+}
+
+/// Suspends the retention policy of a room, placed by a global moderator,
+/// e.g. while its messages are needed as evidence.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LegalHold {
+    pub reason: String,
+    pub placed_by: String,
+    /// Milliseconds since the Unix epoch.
+    pub placed_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NewLegalHold {
+    pub reason: String,
+}
+
+impl NewLegalHold {
+    pub fn validate(self) -> ApiResult<Self> {
+        let mut validator = Validator::default();
+        let reason = validator.content("reason", &self.reason, MAX_REASON_LENGTH);
+        validator.finish()?;
+
+        Ok(NewLegalHold { reason })
+    }
+}
+
+/// The retention settings of a room.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Retention {
+    pub policy: RetentionPolicy,
+    /// Set while pruning is suspended.
+    #[serde(default)]
+    pub legal_hold: Option<LegalHold>,
+}
+
+impl Retention {
+    /// Whether messages are pruned: a policy is set and no legal hold placed.
+    pub fn is_active(&self) -> bool {
+        self.policy.is_set() && self.legal_hold.is_none()
+    }
+
+    /// Number of messages to delete from the start of `messages`, none while
+    /// a legal hold is placed.
+    pub fn expired(&self, messages: &[Message], now: u64) -> usize {
+        if self.legal_hold.is_some() {
+            return 0;
+        }
+
+        self.policy.expired(messages, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 100 * DAY;
+
+    fn message(id: u64, timestamp: u64) -> Message {
+        Message {
+            version: 0,
+            id,
+            author: "alice".to_string(),
+            content: format!("message {id}"),
+            timestamp,
+            nonce: None,
+            edited_at: None,
+            edits: Vec::new(),
+            deleted_at: None,
+            reply_to: None,
+            thread_id: None,
+            reply_count: 0,
+            reactions: Vec::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    fn tombstone(id: u64, timestamp: u64) -> Message {
+        Message {
+            content: String::new(),
+            deleted_at: Some(timestamp),
+            ..message(id, timestamp)
+        }
+    }
+
+    /// Messages posted a day apart, the last one `NOW`.
+    fn daily_messages(count: u64) -> Vec<Message> {
+        (0..count)
+            .map(|id| message(id, NOW - (count - 1 - id) * DAY))
+            .collect()
+    }
+
+    fn policy(max_age_days: Option<u32>, keep_last: Option<u32>) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days,
+            keep_last,
+        }
+    }
+
+    #[test]
+    fn keeps_everything_without_a_policy() {
+        let messages = daily_messages(10);
+        assert_eq!(RetentionPolicy::default().expired(&messages, NOW), 0);
+    }
+
+    #[test]
+    fn expires_messages_older_than_the_max_age() {
+        let messages = daily_messages(10);
+        assert_eq!(policy(Some(3), None).expired(&messages, NOW), 6);
+        assert_eq!(policy(Some(30), None).expired(&messages, NOW), 0);
+    }
+
+    #[test]
+    fn expires_messages_beyond_the_newest_kept() {
+        let messages = daily_messages(10);
+        assert_eq!(policy(None, Some(4)).expired(&messages, NOW), 6);
+        assert_eq!(policy(None, Some(20)).expired(&messages, NOW), 0);
+    }
+
+    #[test]
+    fn does_not_count_tombstones_towards_the_newest_kept() {
+        let mut messages = daily_messages(6);
+        messages[1] = tombstone(1, messages[1].timestamp);
+        messages[4] = tombstone(4, messages[4].timestamp);
+
+        // Messages 2, 3 and 5 are the newest three, so only message 0 goes.
+        assert_eq!(policy(None, Some(3)).expired(&messages, NOW), 1);
+        assert_eq!(policy(None, Some(2)).expired(&messages, NOW), 3);
+    }
+
+    #[test]
+    fn expires_the_most_messages_when_both_limits_are_set() {
+        let messages = daily_messages(10);
+        assert_eq!(policy(Some(3), Some(8)).expired(&messages, NOW), 6);
+        assert_eq!(policy(Some(8), Some(3)).expired(&messages, NOW), 7);
+    }
+
+    #[test]
+    fn expires_nothing_under_a_legal_hold() {
+        let messages = daily_messages(10);
+        let mut retention = Retention {
+            policy: policy(Some(3), Some(2)),
+            legal_hold: None,
+        };
+        assert!(retention.is_active());
+        assert_eq!(retention.expired(&messages, NOW), 8);
+
+        retention.legal_hold = Some(LegalHold {
+            reason: "Investigation".to_string(),
+            placed_by: "moderator".to_string(),
+            placed_at: NOW,
+        });
+        assert!(!retention.is_active());
+        assert_eq!(retention.expired(&messages, NOW), 0);
+    }
+}
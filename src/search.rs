@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use worker::Url;
+
+use crate::chatroom::Message;
+use crate::error::{ApiResult, AppError};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Terms of a query beyond this many are ignored.
+const MAX_QUERY_TERMS: usize = 10;
+
+/// Tokens longer than this many bytes are not indexed.
+const MAX_TOKEN_LENGTH: usize = 64;
+
+/// Approximate length of a snippet, in characters.
+const SNIPPET_LENGTH: usize = 160;
+
+/// A token of a text: a run of letters and digits, lowercased, along with
+/// its byte range in the text.
+struct Token {
+    term: String,
+    start: usize,
+    end: usize,
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = Token> + '_ {
+    let mut chars = text.char_indices().peekable();
+
+    std::iter::from_fn(move || loop {
+        let (start, _) = *chars.peek()?;
+        let mut end = start;
+        while let Some(&(index, c)) = chars.peek() {
+            if !c.is_alphanumeric() {
+                break;
+            }
+            end = index + c.len_utf8();
+            chars.next();
+        }
+
+        if end == start {
+            chars.next();
+            continue;
+        }
+        return Some(Token {
+            term: text[start..end].to_lowercase(),
+            start,
+            end,
+        });
+    })
+    .filter(|token| token.term.len() <= MAX_TOKEN_LENGTH)
+}
+
+/// The terms of a search query, as matched against the index.
+pub fn terms(query: &str) -> Vec<String> {
+    tokenize(query)
+        .map(|token| token.term)
+        .take(MAX_QUERY_TERMS)
+        .collect()
+}
+
+/// Inverted index of the contents of a room's messages, from each term to
+/// the ids of the messages holding it.
+#[derive(Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, BTreeSet<u64>>,
+}
+
+impl SearchIndex {
+    pub fn insert(&mut self, id: u64, content: &str) {
+        for token in tokenize(content) {
+            self.postings.entry(token.term).or_default().insert(id);
+        }
+    }
+
+    pub fn remove(&mut self, id: u64, content: &str) {
+        for token in tokenize(content) {
+            if let Some(ids) = self.postings.get_mut(&token.term) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&token.term);
+                }
+            }
+        }
+    }
+
+    /// The ids of the messages holding a term starting with each of the
+    /// query's terms.
+    pub fn matching(&self, terms: &[String]) -> BTreeSet<u64> {
+        let mut matching: Option<BTreeSet<u64>> = None;
+
+        for prefix in terms {
+            let ids = self
+                .postings
+                .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+                .take_while(|(term, _)| term.starts_with(prefix.as_str()))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect::<BTreeSet<_>>();
+
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+
+        matching.unwrap_or_default()
+    }
+}
+
+/// A search of a room: messages holding every term of `q`, each matched as a
+/// prefix, narrowed by the filters. Hits come newest first, in pages of
+/// `limit` ending before the `before` id.
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub author: Option<String>,
+    /// Milliseconds since the Unix epoch, inclusive.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub has_attachment: Option<bool>,
+    pub before: Option<u64>,
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    pub fn from_url(url: &Url) -> ApiResult<Self> {
+        let mut query = SearchQuery {
+            terms: Vec::new(),
+            author: None,
+            since: None,
+            until: None,
+            has_attachment: None,
+            before: None,
+            limit: DEFAULT_PAGE_SIZE,
+        };
+
+        for (name, value) in url.query_pairs() {
+            let invalid = || AppError::BadRequest(format!("Invalid value for `{name}`: {value}"));
+            match name.as_ref() {
+                "q" => query.terms = terms(&value),
+                "author" => query.author = Some(value.to_string()),
+                "since" => query.since = Some(value.parse().map_err(|_| invalid())?),
+                "until" => query.until = Some(value.parse().map_err(|_| invalid())?),
+                "has_attachment" => {
+                    query.has_attachment = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "before" => query.before = Some(value.parse().map_err(|_| invalid())?),
+                "limit" => match value.parse() {
+                    Ok(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => query.limit = limit,
+                    _ => return Err(invalid()),
+                },
+                _ => {}
+            }
+        }
+
+        if query.terms.is_empty() {
+            return Err(AppError::BadRequest(
+                "A search needs a `q` holding at least one word".to_string(),
+            ));
+        }
+
+        Ok(query)
+    }
+
+    /// Whether a message holding the terms passes the filters.
+    pub fn accepts(&self, author: &str, timestamp: u64, has_attachment: bool) -> bool {
+        self.author.as_deref().is_none_or(|by| author == by)
+            && self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp <= until)
+            && self.has_attachment.is_none_or(|has| has_attachment == has)
+    }
+}
+
+/// A page of search hits, newest first.
+#[derive(Serialize, Deserialize)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    /// Number of messages matching the search, across every page.
+    pub total: usize,
+    /// Pass as `before` to fetch the next, older, page.
+    #[serde(default)]
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchHit {
+    pub message: Message,
+    /// An excerpt of the content around the first match, split into
+    /// fragments so clients can highlight the matched words.
+    pub snippet: Vec<Fragment>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Fragment {
+    pub text: String,
+    pub highlight: bool,
+}
+
+/// An excerpt of `content` of about [`SNIPPET_LENGTH`] characters around the
+/// first word matching one of the terms, with every matching word in it
+/// highlighted. Truncated ends are marked by an ellipsis.
+pub fn snippet(content: &str, terms: &[String]) -> Vec<Fragment> {
+    let matches = tokenize(content)
+        .filter(|token| {
+            terms
+                .iter()
+                .any(|term| token.term.starts_with(term.as_str()))
+        })
+        .map(|token| (token.start, token.end))
+        .collect::<Vec<_>>();
+
+    // Start a few words before the first match, on a character boundary.
+    let first = matches.first().map_or(0, |(start, _)| *start);
+    let lead = content[..first]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_LENGTH / 4)
+        .map_or(0, |(index, _)| index);
+    let start = content[lead..]
+        .char_indices()
+        .find(|(_, c)| c.is_whitespace())
+        .map(|(offset, c)| lead + offset + c.len_utf8())
+        .filter(|start| lead > 0 && *start <= first)
+        .unwrap_or(lead);
+    let end = content[start..]
+        .char_indices()
+        .nth(SNIPPET_LENGTH)
+        .map_or(content.len(), |(index, _)| start + index);
+
+    let mut fragments = Vec::new();
+    let mut push = |text: &str, highlight: bool| {
+        if !text.is_empty() {
+            fragments.push(Fragment {
+                text: text.to_string(),
+                highlight,
+            });
+        }
+    };
+
+    if start > 0 {
+        push("…", false);
+    }
+    let mut position = start;
+    for (match_start, match_end) in matches {
+        if match_start < start || match_end > end {
+            continue;
+        }
+        push(&content[position..match_start], false);
+        push(&content[match_start..match_end], true);
+        position = match_end;
+    }
+    push(&content[position..end], false);
+    if end < content.len() {
+        push("…", false);
+    }
+
+    fragments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(contents: &[&str]) -> SearchIndex {
+        let mut index = SearchIndex::default();
+        for (id, content) in contents.iter().enumerate() {
+            index.insert(id as u64, content);
+        }
+        index
+    }
+
+    fn matching(index: &SearchIndex, query: &str) -> Vec<u64> {
+        index.matching(&terms(query)).into_iter().collect()
+    }
+
+    fn fragment(text: &str, highlight: bool) -> Fragment {
+        Fragment {
+            text: text.to_string(),
+            highlight,
+        }
+    }
+
+    #[test]
+    fn splits_queries_into_lowercase_terms() {
+        assert_eq!(
+            terms("Hello, World! it's 2024"),
+            ["hello", "world", "it", "s", "2024"]
+        );
+        assert_eq!(terms("Ünïcode déjà-vu"), ["ünïcode", "déjà", "vu"]);
+        assert!(terms(" ,.!? ").is_empty());
+    }
+
+    #[test]
+    fn matches_every_term_as_a_prefix() {
+        let index = index(&["Deploy the release", "Release notes", "deploying now"]);
+
+        assert_eq!(matching(&index, "deploy"), [0, 2]);
+        assert_eq!(matching(&index, "REL"), [0, 1]);
+        assert_eq!(matching(&index, "deploy release"), [0]);
+        assert!(matching(&index, "deploy notes").is_empty());
+        assert!(matching(&index, "ployment").is_empty());
+    }
+
+    #[test]
+    fn forgets_removed_contents() {
+        let mut index = index(&["first draft", "second draft"]);
+        index.remove(0, "first draft");
+
+        assert_eq!(matching(&index, "draft"), [1]);
+        assert!(matching(&index, "first").is_empty());
+        assert!(!index.postings.contains_key("first"));
+    }
+
+    #[test]
+    fn highlights_every_match_in_the_snippet() {
+        let snippet = snippet("Ship the release, then tag releases", &terms("release"));
+
+        assert_eq!(
+            snippet,
+            [
+                fragment("Ship the ", false),
+                fragment("release", true),
+                fragment(", then tag ", false),
+                fragment("releases", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_long_snippets_around_the_first_match() {
+        let content = format!("{} needle {}", "hay ".repeat(100), "hay ".repeat(100));
+        let snippet = snippet(&content, &terms("needle"));
+
+        assert_eq!(snippet.first(), Some(&fragment("…", false)));
+        assert_eq!(snippet.last(), Some(&fragment("…", false)));
+        assert!(snippet.contains(&fragment("needle", true)));
+        let length = snippet
+            .iter()
+            .map(|f| f.text.chars().count())
+            .sum::<usize>();
+        assert!(length <= SNIPPET_LENGTH + 2);
+        assert!(snippet[1].text.starts_with("hay"));
+    }
+}
@@ -0,0 +1,44 @@
+//! Helpers around durable object storage.
+//!
+//! Values are stored as JSON strings so they are (de)serialized by
+//! `serde_json` like every other payload in the crate.
+
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen_futures::wasm_bindgen::JsValue;
+use worker::*;
+
+pub async fn get<T: DeserializeOwned>(storage: &Storage, key: &str) -> Result<Option<T>> {
+    let entries = storage.get_multiple(vec![key]).await?;
+
+    decode(entries.get(&key.into()))
+}
+
+pub async fn put<T: Serialize>(storage: &mut Storage, key: &str, value: &T) -> Result<()> {
+    storage.put(key, serde_json::to_string(value)?).await
+}
+
+/// Lists entries in key order, as returned by storage.
+pub async fn list<T: DeserializeOwned>(
+    storage: &Storage,
+    options: ListOptions<'_>,
+) -> Result<Vec<(String, T)>> {
+    let entries = storage.list_with_options(options).await?;
+
+    let mut values = Vec::with_capacity(entries.size() as usize);
+    for entry in entries.entries() {
+        let entry = js_sys::Array::from(&entry?);
+        let key = entry.get(0).as_string().unwrap_or_default();
+        if let Some(value) = decode(entry.get(1))? {
+            values.push((key, value));
+        }
+    }
+
+    Ok(values)
+}
+
+fn decode<T: DeserializeOwned>(value: JsValue) -> Result<Option<T>> {
+    match value.as_string() {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
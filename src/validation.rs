@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::error::AppError;
+
+/// Maximum message length in characters, unless `MAX_MESSAGE_LENGTH` is set.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 4000;
+
+/// Maximum size of a request body accepted by the API, in bytes.
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+const MAX_NONCE_LENGTH: usize = 128;
+
+const MAX_EMOJI_LENGTH: usize = 32;
+
+/// A field of a request that failed validation, reported in the
+/// `invalid-params` of the problem document.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InvalidParam {
+    pub name: String,
+    pub reason: String,
+}
+
+/// Collects the invalid fields of a request.
+#[derive(Default)]
+pub struct Validator {
+    invalid: Vec<InvalidParam>,
+}
+
+impl Validator {
+    pub fn invalid(&mut self, name: &str, reason: impl Into<String>) {
+        self.invalid.push(InvalidParam {
+            name: name.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    /// Normalises message content and checks it is not blank or too long.
+    pub fn content(&mut self, name: &str, content: &str, max_length: usize) -> String {
+        let content = normalize(content);
+        let length = content.chars().count();
+
+        if content.trim().is_empty() {
+            self.invalid(name, "must not be empty");
+        } else if length > max_length {
+            self.invalid(
+                name,
+                format!("must be at most {max_length} characters, got {length}"),
+            );
+        }
+
+        content
+    }
+
+    pub fn nonce(&mut self, name: &str, nonce: Option<&str>) {
+        if nonce.is_some_and(|nonce| nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH) {
+            self.invalid(
+                name,
+                format!("must be between 1 and {MAX_NONCE_LENGTH} bytes"),
+            );
+        }
+    }
+
+    /// Checks a reaction is a Unicode emoji or a `:shortcode:`.
+    pub fn emoji(&mut self, name: &str, emoji: &str) {
+        if !is_valid_emoji(emoji) {
+            self.invalid(name, "must be an emoji or a :shortcode:");
+        }
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        if self.invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(self.invalid))
+        }
+    }
+}
+
+/// Whether a reaction is a custom `:shortcode:`, or plausibly a single Unicode
+/// emoji: a short sequence of non-ASCII symbols, possibly joined by ZWJ and
+/// variation selectors or starting with a keycap base.
+pub fn is_valid_emoji(emoji: &str) -> bool {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LENGTH {
+        return false;
+    }
+
+    if let Some(shortcode) = emoji
+        .strip_prefix(':')
+        .and_then(|emoji| emoji.strip_suffix(':'))
+    {
+        return !shortcode.is_empty()
+            && shortcode
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_+-".contains(c));
+    }
+
+    let mut chars = emoji.chars();
+    let first = chars.next().unwrap_or(' ');
+    let keycap = first.is_ascii_digit() || first == '#' || first == '*';
+
+    (keycap || !first.is_ascii() && !first.is_alphanumeric())
+        && !emoji.is_ascii()
+        && chars
+            .all(|c| !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace() && !c.is_control())
+}
+
+/// Normalises text to NFC and strips control characters other than line
+/// feeds and tabs, which also turns CRLF line breaks into LF.
+pub fn normalize(text: &str) -> String {
+    text.nfc()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .collect()
+}
+
+/// Maximum message length in characters, as set by `MAX_MESSAGE_LENGTH`.
+pub fn max_message_length(env: &worker::Env) -> usize {
+    env.var("MAX_MESSAGE_LENGTH")
+        .ok()
+        .and_then(|value| value.to_string().trim().parse().ok())
+        .filter(|length| *length > 0)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_LENGTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_unicode_emoji() {
+        for emoji in ["👍", "🎉", "❤️", "👩‍💻", "👍🏽", "1️⃣", "#️⃣", "🇫🇷"]
+        {
+            assert!(is_valid_emoji(emoji), "{emoji}");
+        }
+    }
+
+    #[test]
+    fn accepts_shortcodes() {
+        for emoji in [":thumbsup:", ":+1:", ":party_parrot:", ":flag-fr:"] {
+            assert!(is_valid_emoji(emoji), "{emoji}");
+        }
+    }
+
+    #[test]
+    fn rejects_text() {
+        for emoji in [
+            "",
+            "a",
+            "ok",
+            "1",
+            "#",
+            ":",
+            "::",
+            ":Thumbsup:",
+            ":two words:",
+            "é",
+            "日本",
+            "👍 ",
+            "👍a",
+        ] {
+            assert!(!is_valid_emoji(emoji), "{emoji:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_long_sequences() {
+        assert!(!is_valid_emoji(&"👍".repeat(9)));
+        assert!(!is_valid_emoji(&format!(
+            ":{}:",
+            "a".repeat(MAX_EMOJI_LENGTH)
+        )));
+    }
+}